@@ -4,45 +4,253 @@ use crate::tokenizer::Resp;
 
 #[derive(Debug, Clone)]
 pub enum RedisCommands {
-    Echo(String),
+    Echo(Vec<u8>),
     Ping,
     Set(SetOptions),
-    Get(String),
-    Info(Option<InfoSection>),
+    Get(Vec<u8>),
+    Info(Vec<InfoSection>),
     ReplConf(ReplConfMode),
     PSync(String, i64),
     Wait(i32, u64),
-    Config(String, String)
+    Config(String, String),
+    Object(String, Vec<u8>),
+    ReplicaOf(String, String),
+    Command(String, Vec<String>),
+    Client(String, Vec<String>),
+    Debug(String, Vec<Vec<u8>>),
+}
+
+impl RedisCommands {
+    /// Whether the command modifies the keyspace. Writes are refused on replicas and past maxmemory, and are the
+    /// only commands propagated to replicas
+    pub fn is_write(&self) -> bool {
+        match self {
+            RedisCommands::Set(_) => true,
+            RedisCommands::Echo(_)
+            | RedisCommands::Ping
+            | RedisCommands::Get(_)
+            | RedisCommands::Info(_)
+            | RedisCommands::Wait(_, _)
+            | RedisCommands::Object(_, _)
+            | RedisCommands::Command(_, _)
+            | RedisCommands::Client(_, _)
+            | RedisCommands::ReplConf(_)
+            | RedisCommands::PSync(_, _)
+            | RedisCommands::Config(_, _)
+            | RedisCommands::ReplicaOf(_, _)
+            | RedisCommands::Debug(_, _) => false,
+        }
+    }
+}
+
+pub struct CommandDoc {
+    pub name: &'static str,
+    pub summary: &'static str,
+    pub since: &'static str,
+    pub group: &'static str,
+    pub arguments: &'static [(&'static str, &'static str)],
+}
+
+pub const COMMAND_DOCS: &[CommandDoc] = &[
+    CommandDoc {
+        name: "echo",
+        summary: "Returns the given string.",
+        since: "1.0.0",
+        group: "connection",
+        arguments: &[("message", "string")],
+    },
+    CommandDoc {
+        name: "ping",
+        summary: "Returns the server's liveliness response.",
+        since: "1.0.0",
+        group: "connection",
+        arguments: &[],
+    },
+    CommandDoc {
+        name: "set",
+        summary: "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.",
+        since: "1.0.0",
+        group: "string",
+        arguments: &[("key", "key"), ("value", "string"), ("expiration", "oneof")],
+    },
+    CommandDoc {
+        name: "setex",
+        summary: "Sets the string value and expiration time of a key. Creates the key if it doesn't exist.",
+        since: "2.0.0",
+        group: "string",
+        arguments: &[("key", "key"), ("seconds", "integer"), ("value", "string")],
+    },
+    CommandDoc {
+        name: "psetex",
+        summary: "Sets both string value and expiration time in milliseconds of a key. The key is created if it doesn't exist.",
+        since: "2.6.0",
+        group: "string",
+        arguments: &[("key", "key"), ("milliseconds", "integer"), ("value", "string")],
+    },
+    CommandDoc {
+        name: "get",
+        summary: "Returns the string value of a key.",
+        since: "1.0.0",
+        group: "string",
+        arguments: &[("key", "key")],
+    },
+    CommandDoc {
+        name: "info",
+        summary: "Returns information and statistics about the server.",
+        since: "1.0.0",
+        group: "server",
+        arguments: &[("section", "string")],
+    },
+    CommandDoc {
+        name: "replconf",
+        summary: "An internal command for configuring the replication stream.",
+        since: "3.0.0",
+        group: "server",
+        arguments: &[],
+    },
+    CommandDoc {
+        name: "psync",
+        summary: "An internal command used in replication.",
+        since: "2.8.0",
+        group: "server",
+        arguments: &[("replicationid", "string"), ("offset", "integer")],
+    },
+    CommandDoc {
+        name: "wait",
+        summary: "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed.",
+        since: "3.0.0",
+        group: "generic",
+        arguments: &[("numreplicas", "integer"), ("timeout", "integer")],
+    },
+    CommandDoc {
+        name: "config",
+        summary: "A container for server configuration commands.",
+        since: "2.0.0",
+        group: "server",
+        arguments: &[("subcommand", "string"), ("parameter", "string")],
+    },
+    CommandDoc {
+        name: "object",
+        summary: "Returns the internal encoding of a Redis object.",
+        since: "2.2.3",
+        group: "generic",
+        arguments: &[("key", "key")],
+    },
+    CommandDoc {
+        name: "replicaof",
+        summary: "Configures a server as replica of another, or promotes it to a master.",
+        since: "5.0.0",
+        group: "server",
+        arguments: &[("host", "string"), ("port", "integer")],
+    },
+    CommandDoc {
+        name: "client",
+        summary: "A container for client connection commands.",
+        since: "2.6.9",
+        group: "connection",
+        arguments: &[("connection-name", "string")],
+    },
+    CommandDoc {
+        name: "debug",
+        summary: "A container for debugging commands.",
+        since: "1.0.0",
+        group: "server",
+        arguments: &[("subcommand", "string"), ("key", "key")],
+    },
+    CommandDoc {
+        name: "command",
+        summary: "Returns documentary information about one, multiple or all commands.",
+        since: "7.0.0",
+        group: "server",
+        arguments: &[("command-name", "string")],
+    },
+];
+
+impl From<&CommandDoc> for Resp {
+    fn from(val: &CommandDoc) -> Self {
+        let arguments = val
+            .arguments
+            .iter()
+            .map(|(name, arg_type)| {
+                Resp::Array(vec![
+                    Resp::BulkString(b"name".to_vec()),
+                    Resp::BulkString(name.as_bytes().to_vec()),
+                    Resp::BulkString(b"type".to_vec()),
+                    Resp::BulkString(arg_type.as_bytes().to_vec()),
+                ])
+            })
+            .collect();
+        Resp::Array(vec![
+            Resp::BulkString(b"summary".to_vec()),
+            Resp::BulkString(val.summary.as_bytes().to_vec()),
+            Resp::BulkString(b"since".to_vec()),
+            Resp::BulkString(val.since.as_bytes().to_vec()),
+            Resp::BulkString(b"group".to_vec()),
+            Resp::BulkString(val.group.as_bytes().to_vec()),
+            Resp::BulkString(b"arguments".to_vec()),
+            Resp::Array(arguments),
+        ])
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct SetOptions {
-    pub key: String,
-    pub value: String,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
     pub expire: Option<u64>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InfoSection {
+    Clients,
+    Memory,
     Replication,
 }
 
+/// Implemented INFO sections in output order, flagged with whether a bare `INFO` includes them
+const INFO_SECTIONS: &[(InfoSection, &str, bool)] = &[
+    (InfoSection::Clients, "Clients", true),
+    (InfoSection::Memory, "Memory", true),
+    (InfoSection::Replication, "Replication", true),
+];
+
+impl InfoSection {
+    pub fn name(&self) -> &'static str {
+        INFO_SECTIONS
+            .iter()
+            .find(|(section, _, _)| section == self)
+            .map(|(_, name, _)| *name)
+            .unwrap_or_default()
+    }
+
+    pub fn all() -> Vec<InfoSection> {
+        INFO_SECTIONS.iter().map(|(section, _, _)| *section).collect()
+    }
+
+    pub fn defaults() -> Vec<InfoSection> {
+        INFO_SECTIONS
+            .iter()
+            .filter(|(_, _, is_default)| *is_default)
+            .map(|(section, _, _)| *section)
+            .collect()
+    }
+}
+
 impl TryFrom<&str> for InfoSection {
     type Error = anyhow::Error;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value.to_lowercase().as_ref() {
-            "replication" => Ok(InfoSection::Replication),
-            section => Err(anyhow!("info section {section} not supported")),
-        }
+        INFO_SECTIONS
+            .iter()
+            .find(|(_, name, _)| name.eq_ignore_ascii_case(value))
+            .map(|(section, _, _)| *section)
+            .ok_or(anyhow!("info section {value} not supported"))
     }
 }
 
 impl From<InfoSection> for Resp {
     fn from(val: InfoSection) -> Self {
-        match val {
-            InfoSection::Replication => Resp::BulkString("REPLICATION".to_string()),
-        }
+        Resp::BulkString(val.name().to_uppercase().into())
     }
 }
 
@@ -75,14 +283,14 @@ impl From<ReplConfMode> for Vec<Resp> {
     fn from(val: ReplConfMode) -> Self {
         match val {
             ReplConfMode::ListeningPort(port) => vec![
-                Resp::BulkString("LISTENING-PORT".to_string()),
-                Resp::BulkString(port.to_string()),
+                Resp::BulkString(b"LISTENING-PORT".to_vec()),
+                Resp::BulkString(port.to_string().into()),
             ],
-            ReplConfMode::Capability(capa) => vec![Resp::BulkString("CAPA".to_string()), Resp::BulkString(capa)],
-            ReplConfMode::GetAck(ack) => vec![Resp::BulkString("GETACK".to_string()), Resp::BulkString(ack)],
+            ReplConfMode::Capability(capa) => vec![Resp::BulkString(b"CAPA".to_vec()), Resp::BulkString(capa.into())],
+            ReplConfMode::GetAck(ack) => vec![Resp::BulkString(b"GETACK".to_vec()), Resp::BulkString(ack.into())],
             ReplConfMode::Ack(offset) => vec![
-                Resp::BulkString("ACK".to_string()),
-                Resp::BulkString(offset.to_string()),
+                Resp::BulkString(b"ACK".to_vec()),
+                Resp::BulkString(offset.to_string().into()),
             ],
         }
     }
@@ -92,25 +300,32 @@ impl TryFrom<Resp> for RedisCommands {
     type Error = anyhow::Error;
 
     fn try_from(value: Resp) -> Result<Self, Self::Error> {
+        let value = match value {
+            Resp::Attribute(_, value) => *value,
+            value => value,
+        };
         let Resp::Array(array) = value else {
             return Err(anyhow!("Command failed"));
         };
         let Some(Resp::BulkString(command)) = array.first() else {
             return Err(anyhow!("Command failed"));
         };
+        let command = text(command)?;
         match command.to_lowercase().as_ref() {
             "ping" => Ok(RedisCommands::Ping),
             "echo" => match array.get(1) {
-                Some(Resp::BulkString(text)) => Ok(RedisCommands::Echo(text.to_string())),
+                Some(Resp::BulkString(text)) => Ok(RedisCommands::Echo(text.to_owned())),
                 _ => Err(anyhow!("Echo arg not supported")),
             },
             "set" => match array.get(1..3) {
                 Some([Resp::BulkString(key), Resp::BulkString(value)]) => {
                     let expire = match array.get(3..5) {
                         Some([Resp::BulkString(option), Resp::BulkString(value)]) => {
+                            let option = text(option)?;
                             if option.eq_ignore_ascii_case("px") {
-                                let value = value.parse::<u64>()?;
-                                Some(value)
+                                Some(parse_expire(value, 1, "set")?)
+                            } else if option.eq_ignore_ascii_case("ex") {
+                                Some(parse_expire(value, 1000, "set")?)
                             } else {
                                 None
                             }
@@ -118,22 +333,46 @@ impl TryFrom<Resp> for RedisCommands {
                         _ => None,
                     };
                     Ok(RedisCommands::Set(SetOptions {
-                        key: key.to_string(),
-                        value: value.to_string(),
+                        key: key.to_owned(),
+                        value: value.to_owned(),
                         expire,
                     }))
                 }
                 _ => Err(anyhow!("Set arg not supported")),
             },
+            "setex" | "psetex" => match array.get(1..4) {
+                Some([Resp::BulkString(key), Resp::BulkString(ttl), Resp::BulkString(value)]) => {
+                    let unit_millis = if command.eq_ignore_ascii_case("setex") { 1000 } else { 1 };
+                    let expire = parse_expire(ttl, unit_millis, &command.to_lowercase())?;
+                    Ok(RedisCommands::Set(SetOptions {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                        expire: Some(expire),
+                    }))
+                }
+                _ => Err(anyhow!("{} arg not supported", command)),
+            },
             "get" => match array.get(1) {
-                Some(Resp::BulkString(text)) => Ok(RedisCommands::Get(text.to_string())),
+                Some(Resp::BulkString(key)) => Ok(RedisCommands::Get(key.to_owned())),
                 _ => Err(anyhow!("Get arg not supported")),
             },
-            "info" => match array.get(1) {
-                Some(Resp::BulkString(section)) => Ok(RedisCommands::Info(Some(section.as_str().try_into()?))),
-                None => Ok(RedisCommands::Info(None)),
-                _ => Err(anyhow!("Info arg not supported")),
-            },
+            "info" => {
+                let mut sections = vec![];
+                for arg in array.iter().skip(1) {
+                    let Resp::BulkString(section) = arg else {
+                        return Err(anyhow!("Info arg not supported"));
+                    };
+                    match text(section)?.to_lowercase().as_ref() {
+                        "all" | "everything" => sections.extend(InfoSection::all()),
+                        "default" => sections.extend(InfoSection::defaults()),
+                        section => sections.push(section.try_into()?),
+                    }
+                }
+                if sections.is_empty() {
+                    sections = InfoSection::defaults();
+                }
+                Ok(RedisCommands::Info(sections))
+            }
             "replconf" => {
                 let Some(Resp::BulkString(mode)) = array.get(1) else {
                     return Err(anyhow!("ReplConf mode missing"));
@@ -141,7 +380,7 @@ impl TryFrom<Resp> for RedisCommands {
                 let Some(Resp::BulkString(mode_arg)) = array.get(2) else {
                     return Err(anyhow!("ReplConf second arg missing"));
                 };
-                let mode = ReplConfMode::try_from((mode.as_ref(), mode_arg.as_ref()))?;
+                let mode = ReplConfMode::try_from((text(mode)?, text(mode_arg)?))?;
                 Ok(RedisCommands::ReplConf(mode))
             }
             "psync" => {
@@ -151,8 +390,8 @@ impl TryFrom<Resp> for RedisCommands {
                 let Some(Resp::BulkString(repl_offset)) = array.get(2) else {
                     return Err(anyhow!("PSync repl_offset missing"));
                 };
-                let repl_offset = repl_offset.parse::<i64>()?;
-                Ok(RedisCommands::PSync(repl_id.to_string(), repl_offset))
+                let repl_offset = text(repl_offset)?.parse::<i64>()?;
+                Ok(RedisCommands::PSync(text(repl_id)?.to_string(), repl_offset))
             }
             "wait" => {
                 let Some(Resp::BulkString(num_replicas)) = array.get(1) else {
@@ -161,8 +400,8 @@ impl TryFrom<Resp> for RedisCommands {
                 let Some(Resp::BulkString(timeout)) = array.get(2) else {
                     return Err(anyhow!("Wait timeout missing"));
                 };
-                let num_replicas = num_replicas.parse::<i32>()?;
-                let timeout = timeout.parse::<u64>()?;
+                let num_replicas = text(num_replicas)?.parse::<i32>()?;
+                let timeout = text(timeout)?.parse::<u64>()?;
                 Ok(RedisCommands::Wait(num_replicas, timeout))
             },
             "config" => {
@@ -172,61 +411,226 @@ impl TryFrom<Resp> for RedisCommands {
                 let Some(Resp::BulkString(config_key)) = array.get(2) else {
                     return Err(anyhow!("Config key missing"));
                 };
+                let (mode, config_key) = (text(mode)?, text(config_key)?);
                 Ok(RedisCommands::Config(mode.to_owned(), config_key.to_owned()))
             },
-            _ => unimplemented!(),
+            "object" => {
+                let Some(Resp::BulkString(subcommand)) = array.get(1) else {
+                    return Err(anyhow!("Object subcommand missing"));
+                };
+                let Some(Resp::BulkString(key)) = array.get(2) else {
+                    return Err(anyhow!("Object key missing"));
+                };
+                Ok(RedisCommands::Object(text(subcommand)?.to_owned(), key.to_owned()))
+            },
+            "replicaof" | "slaveof" => {
+                let Some(Resp::BulkString(host)) = array.get(1) else {
+                    return Err(anyhow!("ReplicaOf host missing"));
+                };
+                let Some(Resp::BulkString(port)) = array.get(2) else {
+                    return Err(anyhow!("ReplicaOf port missing"));
+                };
+                Ok(RedisCommands::ReplicaOf(text(host)?.to_owned(), text(port)?.to_owned()))
+            },
+            "command" => {
+                let Some(Resp::BulkString(mode)) = array.get(1) else {
+                    return Err(anyhow!("Command subcommand missing"));
+                };
+                let mut command_names = vec![];
+                for arg in array.iter().skip(2) {
+                    let Resp::BulkString(command_name) = arg else {
+                        return Err(anyhow!("Command arg not supported"));
+                    };
+                    command_names.push(text(command_name)?.to_owned());
+                }
+                Ok(RedisCommands::Command(text(mode)?.to_owned(), command_names))
+            },
+            "client" => {
+                let Some(Resp::BulkString(subcommand)) = array.get(1) else {
+                    return Err(anyhow!("Client subcommand missing"));
+                };
+                let mut args = vec![];
+                for arg in array.iter().skip(2) {
+                    let Resp::BulkString(arg) = arg else {
+                        return Err(anyhow!("Client arg not supported"));
+                    };
+                    args.push(text(arg)?.to_owned());
+                }
+                Ok(RedisCommands::Client(text(subcommand)?.to_owned(), args))
+            },
+            "debug" => {
+                let Some(Resp::BulkString(subcommand)) = array.get(1) else {
+                    return Err(anyhow!("Debug subcommand missing"));
+                };
+                let mut args = vec![];
+                for arg in array.iter().skip(2) {
+                    let Resp::BulkString(arg) = arg else {
+                        return Err(anyhow!("Debug arg not supported"));
+                    };
+                    args.push(arg.to_owned());
+                }
+                Ok(RedisCommands::Debug(text(subcommand)?.to_owned(), args))
+            },
+            command => Err(anyhow!("unknown command '{command}'")),
         }
     }
 }
 
+/// Decodes a textual argument such as a command name, subcommand or number; keys and values stay raw bytes
+fn text(bytes: &[u8]) -> anyhow::Result<&str> {
+    std::str::from_utf8(bytes).map_err(|_| anyhow!("argument is not valid UTF-8"))
+}
+
+/// Converts a TTL argument to milliseconds, rejecting the non-positive values Redis refuses
+fn parse_expire(ttl: &[u8], unit_millis: u64, command: &str) -> anyhow::Result<u64> {
+    match text(ttl).ok().and_then(|ttl| ttl.parse::<i64>().ok()) {
+        Some(ttl) if ttl > 0 => (ttl as u64)
+            .checked_mul(unit_millis)
+            .ok_or(anyhow!("invalid expire time in '{command}' command")),
+        Some(_) => Err(anyhow!("invalid expire time in '{command}' command")),
+        None => Err(anyhow!("value is not an integer or out of range")),
+    }
+}
+
 impl From<RedisCommands> for Resp {
     fn from(val: RedisCommands) -> Self {
         match val {
-            RedisCommands::Echo(text) => {
-                Resp::Array(vec![Resp::BulkString("ECHO".to_string()), Resp::BulkString(text)])
-            }
-            RedisCommands::Ping => Resp::Array(vec![Resp::BulkString("PING".to_string())]),
+            RedisCommands::Echo(text) => Resp::Array(vec![Resp::BulkString(b"ECHO".to_vec()), Resp::BulkString(text)]),
+            RedisCommands::Ping => Resp::Array(vec![Resp::BulkString(b"PING".to_vec())]),
             RedisCommands::Set(opts) => {
                 let mut set_cmd = vec![
-                    Resp::BulkString("SET".to_string()),
+                    Resp::BulkString(b"SET".to_vec()),
                     Resp::BulkString(opts.key),
                     Resp::BulkString(opts.value),
                 ];
                 if let Some(expire) = opts.expire {
-                    set_cmd.push(Resp::BulkString("PX".to_string()));
-                    set_cmd.push(Resp::BulkString(expire.to_string()));
+                    set_cmd.push(Resp::BulkString(b"PX".to_vec()));
+                    set_cmd.push(Resp::BulkString(expire.to_string().into()));
                 }
                 Resp::Array(set_cmd)
             }
-            RedisCommands::Get(key) => Resp::Array(vec![Resp::BulkString("GET".to_string()), Resp::BulkString(key)]),
-            RedisCommands::Info(section) => {
-                let mut info_cmd = vec![Resp::BulkString("INFO".to_string())];
-                if let Some(section) = section {
-                    info_cmd.push(section.into());
-                }
+            RedisCommands::Get(key) => Resp::Array(vec![Resp::BulkString(b"GET".to_vec()), Resp::BulkString(key)]),
+            RedisCommands::Info(sections) => {
+                let mut info_cmd = vec![Resp::BulkString(b"INFO".to_vec())];
+                info_cmd.extend(sections.into_iter().map(Resp::from));
                 Resp::Array(info_cmd)
             }
             RedisCommands::ReplConf(mode) => {
-                let mut replconf_cmd = vec![Resp::BulkString("REPLCONF".to_string())];
+                let mut replconf_cmd = vec![Resp::BulkString(b"REPLCONF".to_vec())];
                 let mode_resp: Vec<Resp> = mode.into();
                 replconf_cmd.extend(mode_resp);
                 Resp::Array(replconf_cmd)
             }
             RedisCommands::PSync(repl_id, repl_offset) => Resp::Array(vec![
-                Resp::BulkString("PSYNC".to_string()),
-                Resp::BulkString(repl_id),
-                Resp::BulkString(repl_offset.to_string()),
+                Resp::BulkString(b"PSYNC".to_vec()),
+                Resp::BulkString(repl_id.into()),
+                Resp::BulkString(repl_offset.to_string().into()),
             ]),
             RedisCommands::Wait(num_replicas, timeout) => Resp::Array(vec![
-                Resp::BulkString("WAIT".to_string()),
-                Resp::BulkString(num_replicas.to_string()),
-                Resp::BulkString(timeout.to_string()),
+                Resp::BulkString(b"WAIT".to_vec()),
+                Resp::BulkString(num_replicas.to_string().into()),
+                Resp::BulkString(timeout.to_string().into()),
             ]),
             RedisCommands::Config(mode, key) => Resp::Array(vec![
-                Resp::BulkString("CONFIG".to_string()),
-                Resp::BulkString(mode.to_string()),
-                Resp::BulkString(key.to_string()),
-            ])
+                Resp::BulkString(b"CONFIG".to_vec()),
+                Resp::BulkString(mode.into()),
+                Resp::BulkString(key.into()),
+            ]),
+            RedisCommands::Object(subcommand, key) => Resp::Array(vec![
+                Resp::BulkString(b"OBJECT".to_vec()),
+                Resp::BulkString(subcommand.into()),
+                Resp::BulkString(key),
+            ]),
+            RedisCommands::ReplicaOf(host, port) => Resp::Array(vec![
+                Resp::BulkString(b"REPLICAOF".to_vec()),
+                Resp::BulkString(host.into()),
+                Resp::BulkString(port.into()),
+            ]),
+            RedisCommands::Command(mode, command_names) => {
+                let mut command_cmd = vec![Resp::BulkString(b"COMMAND".to_vec()), Resp::BulkString(mode.into())];
+                command_cmd.extend(command_names.into_iter().map(|name| Resp::BulkString(name.into())));
+                Resp::Array(command_cmd)
+            }
+            RedisCommands::Client(subcommand, args) => {
+                let mut client_cmd = vec![
+                    Resp::BulkString(b"CLIENT".to_vec()),
+                    Resp::BulkString(subcommand.into()),
+                ];
+                client_cmd.extend(args.into_iter().map(|arg| Resp::BulkString(arg.into())));
+                Resp::Array(client_cmd)
+            }
+            RedisCommands::Debug(subcommand, args) => {
+                let mut debug_cmd = vec![Resp::BulkString(b"DEBUG".to_vec()), Resp::BulkString(subcommand.into())];
+                debug_cmd.extend(args.into_iter().map(Resp::BulkString));
+                Resp::Array(debug_cmd)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&[u8]]) -> Resp {
+        Resp::Array(args.iter().map(|arg| Resp::BulkString(arg.to_vec())).collect())
+    }
+
+    #[test]
+    fn command_names_are_case_insensitive() {
+        let set = RedisCommands::try_from(command(&[b"SeT", b"k", b"v"])).unwrap();
+        assert!(matches!(set, RedisCommands::Set(SetOptions { expire: None, .. })));
+
+        let get = RedisCommands::try_from(command(&[b"Get", b"k"])).unwrap();
+        assert!(matches!(get, RedisCommands::Get(key) if key == b"k"));
+    }
+
+    #[test]
+    fn keys_are_case_sensitive() {
+        let upper = RedisCommands::try_from(command(&[b"GET", b"MyKey"])).unwrap();
+        let lower = RedisCommands::try_from(command(&[b"GET", b"mykey"])).unwrap();
+        let (RedisCommands::Get(upper), RedisCommands::Get(lower)) = (upper, lower) else {
+            panic!("GET must parse as RedisCommands::Get");
+        };
+        assert_eq!(upper, b"MyKey");
+        assert_ne!(upper, lower);
+    }
+
+    fn parse_error(args: &[&[u8]]) -> String {
+        RedisCommands::try_from(command(args)).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn zero_ttl_is_rejected() {
+        assert_eq!(
+            parse_error(&[b"SETEX", b"k", b"0", b"v"]),
+            "invalid expire time in 'setex' command"
+        );
+        assert_eq!(
+            parse_error(&[b"PSETEX", b"k", b"-1", b"v"]),
+            "invalid expire time in 'psetex' command"
+        );
+        assert_eq!(
+            parse_error(&[b"SET", b"k", b"v", b"EX", b"0"]),
+            "invalid expire time in 'set' command"
+        );
+        assert_eq!(
+            parse_error(&[b"SET", b"k", b"v", b"px", b"-5"]),
+            "invalid expire time in 'set' command"
+        );
+    }
+
+    #[test]
+    fn ttl_overflow_is_rejected() {
+        let max = i64::MAX.to_string();
+        assert_eq!(
+            parse_error(&[b"SETEX", b"k", max.as_bytes(), b"v"]),
+            "invalid expire time in 'setex' command"
+        );
+        assert_eq!(parse_expire(max.as_bytes(), 1, "psetex").unwrap(), i64::MAX as u64);
+        assert_eq!(
+            parse_error(&[b"SET", b"k", b"v", b"EX", b"ten"]),
+            "value is not an integer or out of range"
+        );
+    }
+}
@@ -1,40 +1,318 @@
 use anyhow::{anyhow, Context};
 use std::{
+    collections::hash_map::RandomState,
     collections::HashMap,
     env,
-    io::{BufRead, BufReader, Write},
-    net::{TcpListener, TcpStream},
+    hash::BuildHasher,
+    io::{BufRead, BufReader, BufWriter, Write},
+    mem::size_of,
+    net::{Shutdown, TcpListener, TcpStream},
     num::ParseIntError,
-    sync::{Arc, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, MutexGuard,
+    },
     thread,
     time::{Duration, SystemTime}, path::PathBuf, str::FromStr,
 };
 
 use crate::{
-    commands::{InfoSection, RedisCommands},
-    tokenizer::{read_next_line, tokenize_bytes, Resp},
+    commands::{InfoSection, RedisCommands, COMMAND_DOCS},
+    sha1::sha1,
+    tokenizer::{tokenize_bytes, FrameScanner, IncompleteFrame, MalformedFrame, Resp},
 };
 
 mod commands;
+mod sha1;
 mod tokenizer;
 
+const DEFAULT_MAXCLIENTS: u64 = 10000;
+
+const REPLICA_ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Estimated bookkeeping cost of a keyspace entry on top of its key and value bytes
+const ENTRY_OVERHEAD: usize = size_of::<Vec<u8>>() + size_of::<Value>();
+
+/// Longest string (in bytes) that Redis stores with the `embstr` encoding
+const OBJ_ENCODING_EMBSTR_SIZE_LIMIT: usize = 44;
+
 const EMPTY_RDB: &str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
 
 struct Value {
-    value: String,
+    value: Vec<u8>,
     expire: Option<u64>,
     timestamp: SystemTime,
 }
 
+impl Value {
+    fn is_expired(&self) -> bool {
+        if let Some(expire) = self.expire {
+            if let Ok(duration) = SystemTime::now().duration_since(self.timestamp) {
+                return duration >= Duration::from_millis(expire);
+            }
+        }
+        false
+    }
+
+    fn estimated_size(&self, key: &[u8]) -> u64 {
+        (key.len() + self.value.len() + ENTRY_OVERHEAD) as u64
+    }
+
+    fn encoding(&self) -> &'static str {
+        let number = std::str::from_utf8(&self.value)
+            .ok()
+            .and_then(|text| text.parse::<i64>().ok());
+        match number {
+            Some(num) if num.to_string().as_bytes() == self.value => "int",
+            _ if self.value.len() <= OBJ_ENCODING_EMBSTR_SIZE_LIMIT => "embstr",
+            _ => "raw",
+        }
+    }
+}
+
+/// The key-value data and its estimated size, kept under one lock so the two always agree
+#[derive(Default)]
+struct Keyspace {
+    entries: HashMap<Vec<u8>, Value>,
+    /// Estimated bytes held by `entries`, adjusted by delta on every change so the maxmemory check stays O(1)
+    used_memory: u64,
+}
+
+impl Keyspace {
+    fn insert(&mut self, key: Vec<u8>, value: Value) {
+        let added_memory = value.estimated_size(&key);
+        let freed_memory = self.entries.get(&key).map(|old| old.estimated_size(&key)).unwrap_or(0);
+        self.entries.insert(key, value);
+        self.used_memory = self.used_memory - freed_memory + added_memory;
+    }
+
+    /// Looks a key up, deleting it first if it has expired, the lazy expiry Redis does on every access
+    fn get(&mut self, key: &[u8]) -> Option<&Value> {
+        if self.entries.get(key).is_some_and(Value::is_expired) {
+            let expired = self.entries.remove(key).expect("the key was just found");
+            self.used_memory -= expired.estimated_size(key);
+        }
+        self.entries.get(key)
+    }
+
+    /// Deletes every expired key, so keys that are never read again still give their memory back
+    fn remove_expired(&mut self) {
+        let mut freed_memory = 0;
+        self.entries.retain(|key, value| {
+            let expired = value.is_expired();
+            if expired {
+                freed_memory += value.estimated_size(key);
+            }
+            !expired
+        });
+        self.used_memory -= freed_memory;
+    }
+}
+
+trait MutexExt<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    /// Locks even if a command panicked while holding the lock: that command already got an internal error reply,
+    /// and the other connections keep going with the state it left behind. The poison is cleared once reported
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            println!(
+                "warning: recovering {} left by a panicked thread",
+                std::any::type_name::<T>()
+            );
+            self.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+}
+
 struct ServerOptions {
     port: u16,
     replicaof: Option<(String, u16)>,
     dir: Option<PathBuf>,
     db_filename: Option<String>,
+    maxclients: u64,
+    maxmemory: u64,
+    client_output_buffer_limits: ClientOutputBufferLimits,
 }
 
 struct ServerStatus {
     server_type: ServerType,
+    clients: HashMap<u64, ClientData>,
+    blocked_clients: u64,
+    dir: Option<PathBuf>,
+    db_filename: Option<String>,
+    maxclients: u64,
+    maxmemory: u64,
+    client_output_buffer_limits: ClientOutputBufferLimits,
+}
+
+/// Pending output a connection may hold: over `hard` bytes, or over `soft` bytes for longer than `soft_seconds`,
+/// gets it disconnected. Zero disables a limit
+#[derive(Clone, Copy)]
+struct OutputBufferLimit {
+    hard: u64,
+    soft: u64,
+    soft_seconds: u64,
+}
+
+/// Limits per client class. Only the replica class is enforced: `normal` and `pubsub` are parsed and reported by
+/// CONFIG GET for compatibility, but ordinary replies are written synchronously and there is no pub/sub
+#[derive(Clone, Copy)]
+struct ClientOutputBufferLimits {
+    normal: OutputBufferLimit,
+    replica: OutputBufferLimit,
+    pubsub: OutputBufferLimit,
+}
+
+impl Default for ClientOutputBufferLimits {
+    fn default() -> Self {
+        Self {
+            normal: OutputBufferLimit {
+                hard: 0,
+                soft: 0,
+                soft_seconds: 0,
+            },
+            replica: OutputBufferLimit {
+                hard: 256 * 1024 * 1024,
+                soft: 64 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            pubsub: OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+        }
+    }
+}
+
+impl ClientOutputBufferLimits {
+    /// Applies a `<class> <hard> <soft> <soft-seconds>` list, leaving classes that are not mentioned untouched
+    fn set_from_config(&mut self, config: &str) -> anyhow::Result<()> {
+        let words = config.split_whitespace().collect::<Vec<_>>();
+        if words.len() % 4 != 0 {
+            return Err(anyhow!(
+                "client-output-buffer-limit expects groups of <class> <hard> <soft> <soft-seconds>"
+            ));
+        }
+        for class_words in words.chunks(4) {
+            let limit = OutputBufferLimit {
+                hard: parse_memory(class_words[1])?,
+                soft: parse_memory(class_words[2])?,
+                soft_seconds: class_words[3]
+                    .parse::<u64>()
+                    .with_context(|| "soft-seconds is not a positive number")?,
+            };
+            match class_words[0].to_ascii_lowercase().as_str() {
+                "normal" => self.normal = limit,
+                "replica" | "slave" => self.replica = limit,
+                "pubsub" => self.pubsub = limit,
+                class => return Err(anyhow!("invalid client class \"{class}\"")),
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_to_config_string(&self) -> String {
+        [
+            ("normal", self.normal),
+            ("slave", self.replica),
+            ("pubsub", self.pubsub),
+        ]
+        .iter()
+        .map(|(class, limit)| format!("{} {} {} {}", class, limit.hard, limit.soft, limit.soft_seconds))
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+}
+
+struct ClientData {
+    name: Option<String>,
+    created_at: SystemTime,
+    last_interaction: SystemTime,
+}
+
+impl Default for ClientData {
+    fn default() -> Self {
+        let now = SystemTime::now();
+        Self {
+            name: None,
+            created_at: now,
+            last_interaction: now,
+        }
+    }
+}
+
+impl ClientData {
+    fn encode_to_info_string(&self, client_id: u64) -> String {
+        let now = SystemTime::now();
+        let age = now.duration_since(self.created_at).unwrap_or_default().as_secs();
+        let idle = now.duration_since(self.last_interaction).unwrap_or_default().as_secs();
+        format!(
+            "id={} name={} age={} idle={}\n",
+            client_id,
+            self.name.as_deref().unwrap_or(""),
+            age,
+            idle
+        )
+    }
+}
+
+/// Keeps a client in the registry for as long as its connection handler runs
+struct ClientGuard {
+    client_id: u64,
+    server_info: Arc<Mutex<ServerStatus>>,
+}
+
+impl ClientGuard {
+    /// Registers the client, or returns `None` when the server already has `maxclients` connections
+    fn register(client_id: u64, server_info: Arc<Mutex<ServerStatus>>) -> Option<Self> {
+        {
+            let mut server_status = server_info.lock_or_recover();
+            if server_status.clients.len() as u64 >= server_status.maxclients {
+                return None;
+            }
+            server_status.clients.insert(client_id, ClientData::default());
+        }
+        Some(ClientGuard { client_id, server_info })
+    }
+
+    /// Marks the client as active now; called when a command arrives and again once it returns, so blocking
+    /// commands count as activity too
+    fn touch(&self) {
+        if let Some(client) = self.server_info.lock_or_recover().clients.get_mut(&self.client_id) {
+            client.last_interaction = SystemTime::now();
+        }
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.server_info.lock_or_recover().clients.remove(&self.client_id);
+    }
+}
+
+/// Counts the client as blocked until dropped, including when the blocking command bails out early
+struct BlockedClientGuard<'a> {
+    server_info: &'a Arc<Mutex<ServerStatus>>,
+}
+
+impl<'a> BlockedClientGuard<'a> {
+    fn new(server_info: &'a Arc<Mutex<ServerStatus>>) -> Self {
+        server_info.lock_or_recover().blocked_clients += 1;
+        BlockedClientGuard { server_info }
+    }
+}
+
+impl Drop for BlockedClientGuard<'_> {
+    fn drop(&mut self) {
+        self.server_info.lock_or_recover().blocked_clients -= 1;
+    }
 }
 
 enum ServerType {
@@ -44,21 +322,75 @@ enum ServerType {
 
 struct MasterStatus {
     repl_id: String,
+    repl_id2: String,
+    second_repl_offset: i64,
     repl_offset: u64,
     repl_data_offset: u64,
     replicas_data: Vec<ReplicaData>,
-    dir: Option<PathBuf>,
-    db_filename: Option<String>
 }
 
 struct ReplicaData {
+    id: u64,
     stream: TcpStream,
+    output: Sender<Vec<u8>>,
+    output_pending: Arc<AtomicU64>,
+    soft_limit_reached_at: Option<SystemTime>,
     latest_offset: u64,
 }
 
 struct ReplicaStatus {
     master_address: String,
     master_port: u16,
+    master_replid: String,
+    repl_offset: u64,
+    master_stream: Option<TcpStream>,
+}
+
+impl ReplicaData {
+    /// Hands `stream` to a dedicated writer thread so slow replicas never block the caller. `synced_offset` is the
+    /// master offset the replica's full resync already covers, so WAIT does not wait for it to ack older writes
+    fn new(id: u64, stream: TcpStream, synced_offset: u64) -> anyhow::Result<Self> {
+        let (output, output_receiver) = mpsc::channel();
+        let output_pending = Arc::new(AtomicU64::new(0));
+        let writer_stream = stream.try_clone()?;
+        let writer_pending = output_pending.clone();
+        thread::spawn(move || write_replica_output(writer_stream, output_receiver, writer_pending));
+        Ok(Self {
+            id,
+            stream,
+            output,
+            output_pending,
+            soft_limit_reached_at: None,
+            latest_offset: synced_offset,
+        })
+    }
+
+    /// Queues `bytes` for the writer thread, returns false if the replica is gone or too far behind
+    fn queue(&mut self, bytes: Vec<u8>, limit: OutputBufferLimit) -> bool {
+        let pending = self.output_pending.fetch_add(bytes.len() as u64, Ordering::Relaxed) + bytes.len() as u64;
+        if limit.hard > 0 && pending >= limit.hard {
+            return false;
+        }
+        if limit.soft > 0 && pending >= limit.soft {
+            let reached_at = *self.soft_limit_reached_at.get_or_insert_with(SystemTime::now);
+            let soft_elapsed = SystemTime::now().duration_since(reached_at).unwrap_or_default();
+            if soft_elapsed > Duration::from_secs(limit.soft_seconds) {
+                return false;
+            }
+        } else {
+            self.soft_limit_reached_at = None;
+        }
+        self.output.send(bytes).is_ok()
+    }
+}
+
+impl MasterStatus {
+    fn acked_replicas(&self, offset: u64) -> i32 {
+        self.replicas_data
+            .iter()
+            .map(|replica_data| (replica_data.latest_offset >= offset) as i32)
+            .sum()
+    }
 }
 
 impl ServerType {
@@ -67,8 +399,10 @@ impl ServerType {
             ServerType::Master(status) => format!(
                 "role:master\r\n\
                     master_replid:{}\r\n\
-                    master_repl_offset:{}",
-                status.repl_id, status.repl_offset
+                    master_replid2:{}\r\n\
+                    master_repl_offset:{}\r\n\
+                    second_repl_offset:{}",
+                status.repl_id, status.repl_id2, status.repl_offset, status.second_repl_offset
             ),
             ServerType::Replica(_) => "role:slave".to_string(),
         }
@@ -81,7 +415,10 @@ fn main() -> anyhow::Result<()> {
         port: 6379,
         replicaof: None,
         dir: None,
-        db_filename: None
+        db_filename: None,
+        maxclients: DEFAULT_MAXCLIENTS,
+        maxmemory: 0,
+        client_output_buffer_limits: ClientOutputBufferLimits::default(),
     };
     let _ = args.next();
     while let Some(arg) = args.next() {
@@ -91,18 +428,26 @@ fn main() -> anyhow::Result<()> {
                 .parse::<u16>()
                 .with_context(|| "port is not a number between 0 and 65536")?;
         } else if arg.eq("--replicaof") {
-            let master_host = args.next().ok_or(anyhow!("replicaof master host not found"))?;
-            let master_port = args.next().ok_or(anyhow!("replicaof master pord not found"))?;
-            let master_port = master_port
-                .parse::<u16>()
-                .with_context(|| "master port is not a number between 0 and 65536")?;
-            server_opts.replicaof = Some((master_host, master_port));
+            server_opts.replicaof = Some(parse_replicaof(&mut args)?);
         } else if arg.eq("--dir") {
             let dir = args.next().ok_or(anyhow!("dir path arg not found"))?;
             server_opts.dir = Some(PathBuf::from_str(&dir)?);
         } else if arg.eq("--dbfilename") {
             let db_filename = args.next().ok_or(anyhow!("dbfilename arg not found"))?;
             server_opts.db_filename = Some(db_filename);
+        } else if arg.eq("--maxclients") {
+            let maxclients = args.next().ok_or(anyhow!("maxclients arg not found"))?;
+            server_opts.maxclients = maxclients
+                .parse::<u64>()
+                .with_context(|| "maxclients is not a positive number")?;
+        } else if arg.eq("--maxmemory") {
+            let maxmemory = args.next().ok_or(anyhow!("maxmemory arg not found"))?;
+            server_opts.maxmemory = maxmemory
+                .parse::<u64>()
+                .with_context(|| "maxmemory is not a number of bytes")?;
+        } else if arg.eq("--client-output-buffer-limit") {
+            let limits = args.next().ok_or(anyhow!("client-output-buffer-limit arg not found"))?;
+            server_opts.client_output_buffer_limits.set_from_config(&limits)?;
         } else {
             return Err(anyhow!("invalid cli arg \"{arg}\""));
         }
@@ -110,38 +455,50 @@ fn main() -> anyhow::Result<()> {
     let listener = TcpListener::bind(format!("127.0.0.1:{}", server_opts.port))?;
     println!("Redis listening on port {}", server_opts.port);
 
-    let redis_map = Arc::new(Mutex::new(HashMap::<String, Value>::new()));
+    let redis_map = Arc::new(Mutex::new(Keyspace::default()));
     let server_type = match server_opts.replicaof {
         Some((master_address, master_port)) => ServerType::Replica(ReplicaStatus {
             master_address,
             master_port,
+            master_replid: String::new(),
+            repl_offset: 0,
+            master_stream: None,
         }),
         None => ServerType::Master(MasterStatus {
             repl_id: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
+            repl_id2: "0".repeat(40),
+            second_repl_offset: -1,
             repl_offset: 0,
             repl_data_offset: 0,
             replicas_data: Vec::new(),
-            dir: server_opts.dir,
-            db_filename: server_opts.db_filename
         }),
     };
 
-    if let ServerType::Replica(replica_status) = &server_type {
-        let replica_info = ReplicaStatus {
-            master_address: replica_status.master_address.clone(),
-            master_port: replica_status.master_port,
-        };
+    let port = server_opts.port;
+    let server_opts = Arc::new(Mutex::new(ServerStatus {
+        server_type,
+        clients: HashMap::new(),
+        blocked_clients: 0,
+        dir: server_opts.dir,
+        db_filename: server_opts.db_filename,
+        maxclients: server_opts.maxclients,
+        maxmemory: server_opts.maxmemory,
+        client_output_buffer_limits: server_opts.client_output_buffer_limits,
+    }));
+
+    if let ServerType::Replica(replica_status) = &server_opts.lock_or_recover().server_type {
+        let master_address = replica_status.master_address.clone();
+        let master_port = replica_status.master_port;
         let redis_map = redis_map.clone();
+        let server_info = server_opts.clone();
         thread::spawn(
-            move || match connect_master(replica_info, server_opts.port, redis_map) {
+            move || match connect_master(&master_address, master_port, port, redis_map, server_info) {
                 Ok(_) => println!("connection with master handled correctly"),
                 Err(err) => println!("{}", err),
             },
         );
     }
 
-    let server_opts = Arc::new(Mutex::new(ServerStatus { server_type }));
-
     let mut socket_id: u64 = 0;
     for stream in listener.incoming() {
         match stream {
@@ -149,13 +506,19 @@ fn main() -> anyhow::Result<()> {
                 let _socket_id = socket_id;
                 let redis_map = redis_map.clone();
                 let server_opts = server_opts.clone();
+                socket_id += 1;
 
+                let Some(client_guard) = ClientGuard::register(_socket_id, server_opts.clone()) else {
+                    println!("rejected connection socket {}: max number of clients reached", _socket_id);
+                    let response = Resp::SimpleError("ERR max number of clients reached".to_string());
+                    let _ = _stream.write_all(&response.encode_to_bytes());
+                    continue;
+                };
                 println!("accepted new connection socket {}", _socket_id);
-                thread::spawn(move || match handle_client(_stream, redis_map, server_opts) {
+                thread::spawn(move || match handle_client(_stream, client_guard, redis_map, server_opts) {
                     Ok(_) => println!("connection {} handled correctly", _socket_id),
                     Err(err) => println!("{}", err),
                 });
-                socket_id += 1;
             }
             Err(e) => {
                 println!("error: {}", e);
@@ -165,16 +528,35 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Reads the master address after `--replicaof`. Config-file style passes "host port" as one argument, the CLI
+/// style as two
+fn parse_replicaof(args: &mut impl Iterator<Item = String>) -> anyhow::Result<(String, u16)> {
+    let master_host = args.next().ok_or(anyhow!("replicaof master host not found"))?;
+    let (master_host, master_port) = match master_host.split_once(char::is_whitespace) {
+        Some((host, port)) => (host.to_string(), port.trim().to_string()),
+        None => {
+            let master_port = args.next().ok_or(anyhow!("replicaof master port not found"))?;
+            (master_host, master_port)
+        }
+    };
+    let master_port = master_port
+        .parse::<u16>()
+        .with_context(|| "master port is not a number between 0 and 65536")?;
+    Ok((master_host, master_port))
+}
+
 fn connect_master(
-    replica_info: ReplicaStatus,
+    master_address: &str,
+    master_port: u16,
     port: u16,
-    redis_map: Arc<Mutex<HashMap<String, Value>>>,
+    redis_map: Arc<Mutex<Keyspace>>,
+    server_info: Arc<Mutex<ServerStatus>>,
 ) -> anyhow::Result<()> {
-    let mut stream = TcpStream::connect(format!("{}:{}", replica_info.master_address, replica_info.master_port))?;
+    let mut stream = TcpStream::connect(format!("{}:{}", master_address, master_port))?;
     let mut buf_reader = BufReader::new(stream.try_clone()?);
 
-    let ping_message = Resp::Array(vec![Resp::BulkString("ping".to_string())]);
-    stream.write_all(ping_message.encode_to_string().as_bytes())?;
+    let ping_message = Resp::Array(vec![Resp::BulkString(b"ping".to_vec())]);
+    stream.write_all(&ping_message.encode_to_bytes())?;
     println!("replica sent ping message");
 
     let bytes = buf_reader.fill_buf()?;
@@ -187,11 +569,11 @@ fn connect_master(
     }
 
     let replconf = Resp::Array(vec![
-        Resp::BulkString("REPLCONF".to_string()),
-        Resp::BulkString("listening-port".to_string()),
-        Resp::BulkString(format!("{}", port)),
+        Resp::BulkString(b"REPLCONF".to_vec()),
+        Resp::BulkString(b"listening-port".to_vec()),
+        Resp::BulkString(format!("{}", port).into()),
     ]);
-    stream.write_all(replconf.encode_to_string().as_bytes())?;
+    stream.write_all(&replconf.encode_to_bytes())?;
     println!("replica sent first replconf message");
 
     let bytes = buf_reader.fill_buf()?;
@@ -204,11 +586,11 @@ fn connect_master(
     }
 
     let replconf = Resp::Array(vec![
-        Resp::BulkString("REPLCONF".to_string()),
-        Resp::BulkString("capa".to_string()),
-        Resp::BulkString("psync2".to_string()),
+        Resp::BulkString(b"REPLCONF".to_vec()),
+        Resp::BulkString(b"capa".to_vec()),
+        Resp::BulkString(b"psync2".to_vec()),
     ]);
-    stream.write_all(replconf.encode_to_string().as_bytes())?;
+    stream.write_all(&replconf.encode_to_bytes())?;
     println!("replica sent second replconf message");
 
     let bytes = buf_reader.fill_buf()?;
@@ -221,11 +603,11 @@ fn connect_master(
     }
 
     let psync = Resp::Array(vec![
-        Resp::BulkString("PSYNC".to_string()),
-        Resp::BulkString("?".to_string()),
-        Resp::BulkString("-1".to_string()),
+        Resp::BulkString(b"PSYNC".to_vec()),
+        Resp::BulkString(b"?".to_vec()),
+        Resp::BulkString(b"-1".to_vec()),
     ]);
-    stream.write_all(psync.encode_to_string().as_bytes())?;
+    stream.write_all(&psync.encode_to_bytes())?;
     println!("replica sent psync message");
 
     let bytes = buf_reader.fill_buf()?;
@@ -233,64 +615,122 @@ fn connect_master(
     let consumed_bytes = bytes.len() - remainder.len();
     buf_reader.consume(consumed_bytes);
     println!("replica handshake received: {:?}", tokens);
-    let mut ack_offset = match tokens {
+    let (master_replid, mut ack_offset) = match tokens {
         Resp::SimpleString(resync_text) if resync_text.starts_with("FULLRESYNC") => {
             let split_text: Vec<&str> = resync_text.split_ascii_whitespace().collect();
-            split_text.get(2).unwrap_or(&"0").parse::<i64>()?
+            let master_replid = split_text.get(1).unwrap_or(&"").to_string();
+            (master_replid, split_text.get(2).unwrap_or(&"0").parse::<i64>()?)
         }
         _ => return Err(anyhow!("wrong response from master")),
     };
-    // Read RDB bytes
-    let bytes = buf_reader.fill_buf()?;
-    let (remainder, rdb_len_line) = read_next_line(bytes)?;
-    let consumed_bytes = bytes.len() - remainder.len();
-    let rdb_bytes_len = String::from_utf8(rdb_len_line[1..].to_vec())?.parse::<usize>()?;
-    buf_reader.consume(consumed_bytes);
-    buf_reader.consume(rdb_bytes_len);
+    if let ServerType::Replica(replica_status) = &mut server_info.lock_or_recover().server_type {
+        replica_status.master_replid = master_replid;
+        replica_status.repl_offset = ack_offset as u64;
+        replica_status.master_stream = Some(stream.try_clone()?);
+    }
+    skip_rdb_transfer(&mut buf_reader)?;
 
+    let ack_stream = stream.try_clone()?;
+    let ack_server_info = server_info.clone();
+    thread::spawn(move || {
+        if let Err(err) = send_periodic_acks(ack_stream, ack_server_info) {
+            println!("replica stopped sending acks: {}", err);
+        }
+    });
+
+    // Bytes read but not yet parsed, so a command split across reads is completed by later reads
+    let mut pending = Vec::new();
+    let mut scanner = FrameScanner::default();
     loop {
         let bytes = buf_reader.fill_buf()?;
         if bytes.is_empty() {
             return Ok(());
         }
+        pending.extend_from_slice(bytes);
+        let read_bytes = bytes.len();
+        buf_reader.consume(read_bytes);
 
-        let remainder = match tokenize_bytes(bytes) {
-            Ok((remainder, tokens)) => {
-                println!("received from master: {:?}", tokens);
-                let command: RedisCommands = tokens.try_into()?;
-                handle_master_command(&command, &mut stream, &redis_map, ack_offset)?;
-                remainder
+        let mut consumed_bytes = 0;
+        while consumed_bytes < pending.len() {
+            let bytes = &pending[consumed_bytes..];
+            let frame_len = match scanner.scan(bytes) {
+                Ok(frame_len) => frame_len,
+                Err(err) if err.is::<IncompleteFrame>() => break,
+                Err(err) => return Err(err.context("master sent an untokenizable frame")),
+            };
+            let (_, tokens) = tokenize_bytes(&bytes[..frame_len]).context("master sent an untokenizable frame")?;
+            println!("received from master: {:?}", tokens);
+            let command: RedisCommands = tokens.try_into()?;
+            let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                handle_master_command(&command, &mut stream, &redis_map, ack_offset)
+            }));
+            match handled {
+                Ok(result) => result?,
+                Err(_) => println!("replica could not apply command from master: {:?} panicked", command),
             }
-            Err(err) => {
-                println!("skip buffer since untokenizable: {}", err);
-                bytes
+            consumed_bytes += frame_len;
+            ack_offset += frame_len as i64;
+            match &mut server_info.lock_or_recover().server_type {
+                ServerType::Replica(replica_status) => replica_status.repl_offset = ack_offset as u64,
+                ServerType::Master(_) => return Ok(()),
             }
+        }
+        pending.drain(..consumed_bytes);
+    }
+}
+
+/// Reads past the `$<len>\r\n<payload>` RDB transfer that follows FULLRESYNC. The payload has no trailing `\r\n`
+/// and may arrive over several reads, so it is consumed by length. Returns the payload length
+fn skip_rdb_transfer(reader: &mut impl BufRead) -> anyhow::Result<usize> {
+    let mut len_line = Vec::new();
+    reader.read_until(b'\n', &mut len_line)?;
+    let rdb_bytes_len = len_line
+        .strip_prefix(b"$")
+        .and_then(|line| line.strip_suffix(b"\r\n"))
+        .and_then(|digits| std::str::from_utf8(digits).ok())
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .ok_or_else(|| anyhow!("invalid RDB transfer header {:?}", String::from_utf8_lossy(&len_line)))?;
+    let mut rdb_bytes_left = rdb_bytes_len;
+    while rdb_bytes_left > 0 {
+        let bytes = reader.fill_buf()?;
+        if bytes.is_empty() {
+            return Err(anyhow!("master closed the connection during the RDB transfer"));
+        }
+        let consumed_bytes = bytes.len().min(rdb_bytes_left);
+        reader.consume(consumed_bytes);
+        rdb_bytes_left -= consumed_bytes;
+    }
+    Ok(rdb_bytes_len)
+}
+
+/// Advertises the processed offset to the master every second, so WAIT sees fresh offsets without a GETACK
+fn send_periodic_acks(mut stream: TcpStream, server_info: Arc<Mutex<ServerStatus>>) -> anyhow::Result<()> {
+    loop {
+        thread::sleep(REPLICA_ACK_PERIOD);
+        let ack_offset = match &server_info.lock_or_recover().server_type {
+            ServerType::Replica(replica_status) => replica_status.repl_offset,
+            ServerType::Master(_) => return Ok(()),
         };
-        let consumed_bytes = bytes.len() - remainder.len();
-        ack_offset += consumed_bytes as i64;
-        buf_reader.consume(consumed_bytes);
+        let ack_command = RedisCommands::ReplConf(commands::ReplConfMode::Ack(ack_offset as i64));
+        stream.write_all(&Resp::from(ack_command).encode_to_bytes())?;
     }
 }
 
 fn handle_master_command(
     command: &RedisCommands,
     stream: &mut TcpStream,
-    redis_map: &Arc<Mutex<HashMap<String, Value>>>,
+    redis_map: &Arc<Mutex<Keyspace>>,
     ack_offset: i64,
 ) -> anyhow::Result<()> {
     match command {
         RedisCommands::Ping => {
             println!("replica received ping from master");
         }
-        RedisCommands::Set(opts) => {
-            redis_map.lock().unwrap().insert(
-                opts.key.to_string(),
-                Value {
-                    value: opts.value.to_string(),
-                    expire: opts.expire,
-                    timestamp: SystemTime::now(),
-                },
-            );
+        command if command.is_write() => {
+            // Dropping the link over one command would force a full resync, so log it and keep streaming
+            if let Err(err) = apply_write_command(command, &mut redis_map.lock_or_recover()) {
+                println!("replica could not apply command from master: {}", err);
+            }
         }
         RedisCommands::ReplConf(commands::ReplConfMode::GetAck(_)) => {
             let response = RedisCommands::ReplConf(commands::ReplConfMode::Ack(ack_offset));
@@ -305,170 +745,241 @@ fn handle_master_command(
 
 fn handle_client(
     mut stream: TcpStream,
-    redis_map: Arc<Mutex<HashMap<String, Value>>>,
+    client_guard: ClientGuard,
+    redis_map: Arc<Mutex<Keyspace>>,
     server_opts: Arc<Mutex<ServerStatus>>,
 ) -> anyhow::Result<()> {
     let mut buf_reader = BufReader::new(stream.try_clone()?);
+    // Bytes read but not yet parsed, so a frame split across reads is completed by later reads
+    let mut pending = Vec::new();
+    let mut scanner = FrameScanner::default();
     loop {
         let bytes = buf_reader.fill_buf()?;
         if bytes.is_empty() {
+            // Complete frames were served as they arrived, so anything left is a truncated frame to drop
             return Ok(());
         }
+        pending.extend_from_slice(bytes);
+        let read_bytes = bytes.len();
+        buf_reader.consume(read_bytes);
 
-        let remainder = match tokenize_bytes(bytes) {
-            Ok((remainder, tokens)) => {
-                println!("received: {:?}", tokens);
-                let command: RedisCommands = tokens.try_into()?;
-                handle_command(&command, &mut stream, &redis_map, &server_opts)?;
-                if let RedisCommands::PSync(_, _) = command {
-                    if let ServerType::Master(ref mut master_status) = server_opts.lock().unwrap().server_type {
-                        let stream_clone = stream.try_clone()?;
-                        let server_state = server_opts.clone();
-                        let index = master_status.replicas_data.len();
-                        thread::spawn(move || {
-                            handle_replica_commands(stream_clone, server_state, index).unwrap();
-                        });
-                        master_status.replicas_data.push(ReplicaData {
-                            stream,
-                            latest_offset: 0,
-                        });
-                        println!("master added a replica");
-                        return Ok(());
+        let mut consumed_bytes = 0;
+        while consumed_bytes < pending.len() {
+            let bytes = &pending[consumed_bytes..];
+            // Blank lines and empty arrays are keepalives, Redis ignores them without replying
+            if bytes.starts_with(b"\r\n") {
+                consumed_bytes += 2;
+                continue;
+            }
+
+            let frame_len = match scanner.scan(bytes) {
+                Ok(frame_len) => frame_len,
+                Err(err) if err.is::<IncompleteFrame>() => break,
+                Err(err) => {
+                    println!("skip malformed frame: {}", err);
+                    let response = Resp::SimpleError(format!("ERR Protocol error: {}", err));
+                    stream.write_all(&response.encode_to_bytes())?;
+                    let malformed = err.downcast_ref::<MalformedFrame>();
+                    consumed_bytes += malformed.map_or(bytes.len(), |malformed| malformed.resume_at);
+                    continue;
+                }
+            };
+            consumed_bytes += frame_len;
+            match tokenize_bytes(&bytes[..frame_len]) {
+                Ok((_, Resp::Array(array))) if array.is_empty() => {}
+                Ok((_, tokens)) => {
+                    println!("received: {:?}", tokens);
+                    client_guard.touch();
+                    let command = match RedisCommands::try_from(tokens) {
+                        Ok(command) => Some(command),
+                        Err(err) => {
+                            let response = Resp::SimpleError(format!("ERR {}", err));
+                            stream.write_all(&response.encode_to_bytes())?;
+                            None
+                        }
+                    };
+                    if let Some(RedisCommands::PSync(_, _)) = command {
+                        let replica_id = client_guard.client_id;
+                        if start_full_resync(&mut stream, replica_id, &server_opts)? {
+                            let server_state = server_opts.clone();
+                            thread::spawn(move || {
+                                if let Err(err) = handle_replica_commands(stream, server_state, replica_id) {
+                                    println!("replica {} link closed: {}", replica_id, err);
+                                }
+                            });
+                            println!("master added a replica");
+                            return Ok(());
+                        }
+                    } else if let Some(command) = command {
+                        let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                            handle_command(&command, &mut stream, client_guard.client_id, &redis_map, &server_opts)
+                        }));
+                        match handled {
+                            Ok(result) => result?,
+                            Err(_) => {
+                                let response = Resp::SimpleError("ERR internal error".to_string());
+                                stream.write_all(&response.encode_to_bytes())?;
+                            }
+                        }
                     }
+                    client_guard.touch();
+                }
+                Err(err) => {
+                    println!("skip frame since untokenizable: {}", err);
+                    let response = Resp::SimpleError(format!("ERR Protocol error: {}", err));
+                    stream.write_all(&response.encode_to_bytes())?;
                 }
-                remainder
-            }
-            Err(err) => {
-                println!("skip buffer since untokenizable: {}", err);
-                bytes
             }
-        };
-        let consumed_bytes = bytes.len() - remainder.len();
-        buf_reader.consume(consumed_bytes);
+        }
+        pending.drain(..consumed_bytes);
+    }
+}
+
+/// Answers PSYNC with a full resync, partial resyncs are not supported, and registers the connection as a replica.
+/// Returns false if this server is not a master. The FULLRESYNC offset is read, its payload queued and the replica
+/// registered under one lock, so the replica receives exactly the writes propagated after that offset
+fn start_full_resync(
+    stream: &mut TcpStream,
+    replica_id: u64,
+    server_info: &Arc<Mutex<ServerStatus>>,
+) -> anyhow::Result<bool> {
+    let mut server_status = server_info.lock_or_recover();
+    let limit = server_status.client_output_buffer_limits.replica;
+    let ServerType::Master(master_status) = &mut server_status.server_type else {
+        drop(server_status);
+        let response = Resp::SimpleError("ERR PSYNC is not supported on a replica".to_string());
+        stream.write_all(&response.encode_to_bytes())?;
+        return Ok(false);
+    };
+    let response = Resp::SimpleString(format!(
+        "FULLRESYNC {} {}",
+        master_status.repl_id, master_status.repl_offset
+    ));
+    let empty_rdb_bytes = decode_hex(EMPTY_RDB)?;
+    let mut resync_bytes = response.encode_to_bytes();
+    resync_bytes.extend_from_slice(format!("${}\r\n", empty_rdb_bytes.len()).as_bytes());
+    resync_bytes.extend_from_slice(&empty_rdb_bytes);
+
+    let mut replica_data = ReplicaData::new(replica_id, stream.try_clone()?, master_status.repl_offset)?;
+    if !replica_data.queue(resync_bytes, limit) {
+        return Err(anyhow!("full resync payload exceeds the replica output buffer limit"));
     }
+    master_status.replicas_data.push(replica_data);
+    Ok(true)
 }
 
 fn handle_command(
     command: &RedisCommands,
     stream: &mut impl Write,
-    redis_map: &Arc<Mutex<HashMap<String, Value>>>,
+    client_id: u64,
+    redis_map: &Arc<Mutex<Keyspace>>,
     server_info: &Arc<Mutex<ServerStatus>>,
 ) -> anyhow::Result<()> {
+    if command.is_write() {
+        if let ServerType::Replica(_) = server_info.lock_or_recover().server_type {
+            let response = Resp::SimpleError("READONLY You can't write against a read only replica.".to_string());
+            stream.write_all(&response.encode_to_bytes())?;
+            return Ok(());
+        }
+        let maxmemory = server_info.lock_or_recover().maxmemory;
+        let mut keyspace = redis_map.lock_or_recover();
+        if maxmemory > 0 && keyspace.used_memory > maxmemory {
+            keyspace.remove_expired();
+        }
+        let out_of_memory = maxmemory > 0 && keyspace.used_memory > maxmemory;
+        drop(keyspace);
+        if out_of_memory {
+            let response = Resp::SimpleError("OOM command not allowed when used memory > 'maxmemory'.".to_string());
+            stream.write_all(&response.encode_to_bytes())?;
+            return Ok(());
+        }
+    }
+
     let response = match command {
-        RedisCommands::Echo(text) => Resp::SimpleString(text.to_string()),
+        RedisCommands::Echo(text) => Resp::BulkString(text.clone()),
         RedisCommands::Ping => Resp::SimpleString("PONG".to_string()),
-        RedisCommands::Set(options) => {
-            redis_map.lock().unwrap().insert(
-                options.key.to_string(),
-                Value {
-                    value: options.value.to_string(),
-                    expire: options.expire,
-                    timestamp: SystemTime::now(),
-                },
-            );
-            match server_info.lock().unwrap().server_type {
-                ServerType::Master(ref mut master_status) => {
-                    let set_command = Resp::from(command.clone());
-                    master_status.repl_offset += set_command.encode_to_bytes().len() as u64;
-                    master_status.repl_data_offset = master_status.repl_offset;
-                    for replica_data in &mut master_status.replicas_data {
-                        replica_data.stream.write_all(&set_command.encode_to_bytes())?;
-                    }
-                }
-                ServerType::Replica(_) => {
-                    unimplemented!()
-                }
-            };
-
-            Resp::SimpleString("OK".to_string())
-        }
+        RedisCommands::Set(_) => apply_and_propagate(command, redis_map, server_info)?,
         RedisCommands::Get(key) => {
-            let value = redis_map
-                .lock()
-                .unwrap()
-                .get(key)
-                .filter(|k| {
-                    if let Some(expire) = k.expire {
-                        if let Ok(duration) = SystemTime::now().duration_since(k.timestamp) {
-                            return duration < Duration::from_millis(expire);
-                        }
-                    }
-                    true
-                })
-                .map(|k| k.value.to_string());
+            let value = redis_map.lock_or_recover().get(key).map(|k| k.value.clone());
             if let Some(value) = value {
                 Resp::BulkString(value)
             } else {
                 Resp::NullBulkString
             }
         }
-        RedisCommands::Info(info_section) => match info_section {
-            Some(InfoSection::Replication) => {
-                let info = server_info.lock().unwrap().server_type.encode_to_info_string();
-                Resp::BulkString(info)
-            }
-            None => {
-                let info = server_info.lock().unwrap().server_type.encode_to_info_string();
-                Resp::BulkString(info)
-            }
-        },
+        RedisCommands::Info(sections) => {
+            let used_memory = redis_map.lock_or_recover().used_memory;
+            let server_status = server_info.lock_or_recover();
+            let info = InfoSection::all()
+                .into_iter()
+                .filter(|section| sections.contains(section))
+                .map(|section| {
+                    let fields = match section {
+                        InfoSection::Clients => format!(
+                            "connected_clients:{}\r\n\
+                                blocked_clients:{}\r\n\
+                                maxclients:{}",
+                            server_status.clients.len(),
+                            server_status.blocked_clients,
+                            server_status.maxclients
+                        ),
+                        InfoSection::Memory => format!(
+                            "used_memory:{}\r\n\
+                                used_memory_human:{}\r\n\
+                                maxmemory:{}\r\n\
+                                mem_fragmentation_ratio:1.00",
+                            used_memory,
+                            bytes_to_human(used_memory),
+                            server_status.maxmemory
+                        ),
+                        InfoSection::Replication => server_status.server_type.encode_to_info_string(),
+                    };
+                    format!("# {}\r\n{}\r\n", section.name(), fields)
+                })
+                .collect::<Vec<_>>()
+                .join("\r\n");
+            Resp::BulkString(info.into())
+        }
         RedisCommands::ReplConf(_) => Resp::SimpleString("OK".to_string()),
-        RedisCommands::PSync(repl_id, repl_offset) => match (repl_id.as_ref(), repl_offset) {
-            ("?", -1) => {
-                let (master_repl_id, master_repl_offset) = match &server_info.lock().unwrap().server_type {
-                    ServerType::Master(master_status) => (master_status.repl_id.clone(), master_status.repl_offset),
-                    ServerType::Replica(_) => unimplemented!(),
-                };
-                let response = Resp::SimpleString(format!("FULLRESYNC {} {}", master_repl_id, master_repl_offset));
-                let empty_rdb_bytes = decode_hex(EMPTY_RDB)?;
-                let empty_rdb_bytes = [
-                    b"$",
-                    empty_rdb_bytes.len().to_string().as_bytes(),
-                    b"\r\n",
-                    &empty_rdb_bytes,
-                ]
-                .concat();
-                stream.write_all(&[&response.encode_to_bytes(), empty_rdb_bytes.as_slice()].concat())?;
-                Resp::Empty
-            }
-            _ => unimplemented!(),
-        },
+        // Needs the connection itself to register it as a replica, see `start_full_resync`
+        RedisCommands::PSync(_, _) => Resp::SimpleError("ERR PSYNC is only valid on a client connection".to_string()),
         RedisCommands::Wait(num_replicas, timeout) => {
             let start_time = SystemTime::now();
-            let (mut replica_streams, master_data_offset) = match server_info.lock().unwrap().server_type {
+            let (num_connected_replicas, master_data_offset) = match server_info.lock_or_recover().server_type {
                 ServerType::Master(ref master_status) => {
-                    let mut streams = vec![];
-                    for replica_data in &master_status.replicas_data {
-                        streams.push(replica_data.stream.try_clone()?)
-                    }
-                    (streams, master_status.repl_data_offset)
+                    (master_status.replicas_data.len(), master_status.repl_data_offset)
                 }
-                ServerType::Replica(_) => (vec![], 0),
+                ServerType::Replica(_) => (0, 0),
             };
 
             if master_data_offset == 0 {
-                Resp::Integer(replica_streams.len() as i64)
+                Resp::Integer(num_connected_replicas as i64)
+            } else if *num_replicas <= 0 {
+                let replica_oks = if let ServerType::Master(state) = &server_info.lock_or_recover().server_type {
+                    state.acked_replicas(master_data_offset)
+                } else {
+                    0
+                };
+                Resp::Integer(replica_oks as i64)
             } else {
                 println!("[wait]: master_offset: {}", master_data_offset);
                 let num_replicas = *num_replicas;
+                let _blocked_guard = BlockedClientGuard::new(server_info);
 
                 let mut last_replica_oks = 0;
-                for stream in replica_streams.as_mut_slice() {
-                    let getack_command = RedisCommands::ReplConf(commands::ReplConfMode::GetAck("*".to_string()));
-                    stream.write_all(&Resp::from(getack_command).encode_to_bytes())?;
+                {
+                    let server_status = &mut *server_info.lock_or_recover();
+                    if let ServerType::Master(master_status) = &mut server_status.server_type {
+                        let getack_command = RedisCommands::ReplConf(commands::ReplConfMode::GetAck("*".to_string()));
+                        let getack_bytes = Resp::from(getack_command).encode_to_bytes();
+                        let limit = server_status.client_output_buffer_limits.replica;
+                        master_status.repl_offset += getack_bytes.len() as u64;
+                        queue_to_replicas(master_status, limit, &getack_bytes);
+                    };
                 }
-                if let ServerType::Master(master_status) = &mut server_info.lock().unwrap().server_type {
-                    let getack_command = RedisCommands::ReplConf(commands::ReplConfMode::GetAck("*".to_string()));
-                    let getack_resp = Resp::from(getack_command.clone());
-                    master_status.repl_offset += getack_resp.encode_to_bytes().len() as u64;
-                };
                 let replica_oks = loop {
-                    let replica_oks = if let ServerType::Master(state) = &server_info.lock().unwrap().server_type {
-                        state
-                            .replicas_data
-                            .iter()
-                            .map(|replica_data| (replica_data.latest_offset >= master_data_offset) as i32)
-                            .sum()
+                    let replica_oks = if let ServerType::Master(state) = &server_info.lock_or_recover().server_type {
+                        state.acked_replicas(master_data_offset)
                     } else {
                         0
                     };
@@ -485,76 +996,1137 @@ fn handle_command(
         },
         RedisCommands::Config(mode, config_key) => {
             if mode.eq_ignore_ascii_case("GET") {
+                // Parameter names are case-insensitive and replies use the canonical lowercase name
+                let config_key = config_key.to_ascii_lowercase();
                 match config_key.as_str() {
-                    "dir" => match &server_info.lock().unwrap().server_type {
-                        ServerType::Master(state) => {
-                            let dir = state.dir.as_ref().map(|dir| dir.to_str().unwrap_or("")).unwrap_or("");
-                            Resp::Array(vec![
-                                Resp::BulkString("dir".to_owned()),
-                                Resp::BulkString(dir.to_owned())
-                            ])
-                        },
-                        ServerType::Replica(_) => unimplemented!()
-                    },
-                    "dbfilename" => match &server_info.lock().unwrap().server_type {
-                        ServerType::Master(state) => {
-                            let db_filename = state.db_filename.as_deref().unwrap_or("");
-                            Resp::Array(vec![
-                                Resp::BulkString(config_key.to_owned()),
-                                Resp::BulkString(db_filename.to_owned())
-                            ])
-                        },
-                        ServerType::Replica(_) => unimplemented!()
-                    },
-                    _ => unimplemented!()
+                    "dir" => {
+                        let dir = server_info.lock_or_recover().dir.clone();
+                        let dir = dir.as_ref().map(|dir| dir.to_str().unwrap_or("")).unwrap_or("");
+                        Resp::Array(vec![
+                            Resp::BulkString(config_key.as_bytes().to_vec()),
+                            Resp::BulkString(dir.as_bytes().to_vec()),
+                        ])
+                    }
+                    "dbfilename" => {
+                        let db_filename = server_info.lock_or_recover().db_filename.clone();
+                        Resp::Array(vec![
+                            Resp::BulkString(config_key.as_bytes().to_vec()),
+                            Resp::BulkString(db_filename.unwrap_or_default().into()),
+                        ])
+                    }
+                    "maxmemory" => {
+                        let maxmemory = server_info.lock_or_recover().maxmemory;
+                        Resp::Array(vec![
+                            Resp::BulkString(config_key.as_bytes().to_vec()),
+                            Resp::BulkString(maxmemory.to_string().into()),
+                        ])
+                    }
+                    "maxclients" => {
+                        let maxclients = server_info.lock_or_recover().maxclients;
+                        Resp::Array(vec![
+                            Resp::BulkString(config_key.as_bytes().to_vec()),
+                            Resp::BulkString(maxclients.to_string().into()),
+                        ])
+                    }
+                    "client-output-buffer-limit" => {
+                        let limits = server_info.lock_or_recover().client_output_buffer_limits;
+                        Resp::Array(vec![
+                            Resp::BulkString(config_key.as_bytes().to_vec()),
+                            Resp::BulkString(limits.encode_to_config_string().into()),
+                        ])
+                    }
+                    _ => Resp::Array(vec![]),
+                }
+            } else {
+                Resp::SimpleError(format!("ERR CONFIG {} is not supported", mode.to_uppercase()))
+            }
+        }
+        RedisCommands::ReplicaOf(host, port) => {
+            if host.eq_ignore_ascii_case("NO") && port.eq_ignore_ascii_case("ONE") {
+                let mut server_status = server_info.lock_or_recover();
+                if let ServerType::Replica(replica_status) = &server_status.server_type {
+                    if let Some(master_stream) = &replica_status.master_stream {
+                        let _ = master_stream.shutdown(Shutdown::Both);
+                    }
+                    let master_status = MasterStatus {
+                        repl_id: generate_repl_id(),
+                        repl_id2: replica_status.master_replid.clone(),
+                        second_repl_offset: replica_status.repl_offset as i64 + 1,
+                        repl_offset: replica_status.repl_offset,
+                        repl_data_offset: replica_status.repl_offset,
+                        replicas_data: Vec::new(),
+                    };
+                    println!("replica promoted to master at offset {}", master_status.repl_offset);
+                    server_status.server_type = ServerType::Master(master_status);
                 }
+                Resp::SimpleString("OK".to_string())
             } else {
-                unimplemented!()
+                Resp::SimpleError("ERR REPLICAOF <host> <port> is not supported".to_string())
+            }
+        }
+        RedisCommands::Client(subcommand, args) => {
+            if subcommand.eq_ignore_ascii_case("SETNAME") {
+                match args.first() {
+                    Some(name) if !name.bytes().all(|c| (b'!'..=b'~').contains(&c)) => Resp::SimpleError(
+                        "ERR Client names cannot contain spaces, newlines or special characters.".to_string(),
+                    ),
+                    Some(name) => {
+                        if let Some(client) = server_info.lock_or_recover().clients.get_mut(&client_id) {
+                            client.name = Some(name.to_owned()).filter(|name| !name.is_empty());
+                        }
+                        Resp::SimpleString("OK".to_string())
+                    }
+                    None => Resp::SimpleError("ERR wrong number of arguments for 'client|setname' command".to_string()),
+                }
+            } else if subcommand.eq_ignore_ascii_case("GETNAME") {
+                let name = server_info
+                    .lock_or_recover()
+                    .clients
+                    .get(&client_id)
+                    .and_then(|client| client.name.clone());
+                Resp::BulkString(name.unwrap_or_default().into())
+            } else if subcommand.eq_ignore_ascii_case("LIST") {
+                let server_status = server_info.lock_or_recover();
+                let mut client_ids = server_status.clients.keys().collect::<Vec<_>>();
+                client_ids.sort();
+                let client_list = client_ids
+                    .into_iter()
+                    .map(|id| server_status.clients[id].encode_to_info_string(*id))
+                    .collect::<String>();
+                Resp::BulkString(client_list.into())
+            } else if subcommand.eq_ignore_ascii_case("INFO") {
+                let client_info = server_info
+                    .lock_or_recover()
+                    .clients
+                    .get(&client_id)
+                    .map(|client| client.encode_to_info_string(client_id));
+                Resp::BulkString(client_info.unwrap_or_default().into())
+            } else {
+                Resp::SimpleError(format!("ERR unknown subcommand '{}'", subcommand))
+            }
+        }
+        RedisCommands::Debug(subcommand, args) => {
+            if subcommand.eq_ignore_ascii_case("DIGEST") {
+                let dataset_digest = dataset_digest(&redis_map.lock_or_recover().entries);
+                Resp::SimpleString(encode_hex(&dataset_digest))
+            } else if subcommand.eq_ignore_ascii_case("DIGEST-VALUE") {
+                let mut redis_map = redis_map.lock_or_recover();
+                let digests = args
+                    .iter()
+                    .map(|key| {
+                        let mut value_digest = [0u8; 20];
+                        if let Some(value) = redis_map.get(key) {
+                            mix_object_digest(&mut value_digest, value);
+                        }
+                        Resp::SimpleString(encode_hex(&value_digest))
+                    })
+                    .collect();
+                Resp::Array(digests)
+            } else {
+                Resp::SimpleError(format!("ERR unknown subcommand '{}'", subcommand))
+            }
+        }
+        RedisCommands::Command(mode, command_names) => {
+            if mode.eq_ignore_ascii_case("DOCS") {
+                let mut docs = vec![];
+                for doc in COMMAND_DOCS {
+                    let requested = command_names.iter().any(|name| name.eq_ignore_ascii_case(doc.name));
+                    if command_names.is_empty() || requested {
+                        docs.push(Resp::BulkString(doc.name.to_string().into()));
+                        docs.push(Resp::from(doc));
+                    }
+                }
+                Resp::Array(docs)
+            } else {
+                Resp::SimpleError(format!("ERR unknown subcommand '{}'", mode))
+            }
+        }
+        RedisCommands::Object(subcommand, key) => {
+            if subcommand.eq_ignore_ascii_case("ENCODING") {
+                let encoding = redis_map.lock_or_recover().get(key).map(|k| k.encoding());
+                match encoding {
+                    Some(encoding) => Resp::BulkString(encoding.to_string().into()),
+                    None => Resp::NullBulkString,
+                }
+            } else {
+                Resp::SimpleError(format!("ERR unknown subcommand '{}'", subcommand))
             }
         }
     };
-    stream.write_all(response.encode_to_string().as_bytes())?;
+    let mut writer = BufWriter::new(stream);
+    response.write_to(&mut writer)?;
+    writer.flush()?;
     Ok(())
 }
 
+/// Applies a write and queues it for the replicas before the keyspace lock is released, so concurrent writes reach
+/// the replicas in the order they were applied. Nesting takes the keyspace lock first, then the server lock
+fn apply_and_propagate(
+    command: &RedisCommands,
+    redis_map: &Arc<Mutex<Keyspace>>,
+    server_info: &Arc<Mutex<ServerStatus>>,
+) -> anyhow::Result<Resp> {
+    let mut keyspace = redis_map.lock_or_recover();
+    let response = apply_write_command(command, &mut keyspace)?;
+    propagate_to_replicas(command, server_info)?;
+    Ok(response)
+}
+
+/// Applies a write command to the keyspace, shared by client connections and the replica link to the master
+fn apply_write_command(command: &RedisCommands, keyspace: &mut Keyspace) -> anyhow::Result<Resp> {
+    match command {
+        RedisCommands::Set(options) => {
+            let value = Value {
+                value: options.value.clone(),
+                expire: options.expire,
+                timestamp: SystemTime::now(),
+            };
+            keyspace.insert(options.key.clone(), value);
+            Ok(Resp::SimpleString("OK".to_string()))
+        }
+        _ => Err(anyhow!("{:?} is not a write command", command)),
+    }
+}
+
+fn propagate_to_replicas(command: &RedisCommands, server_info: &Arc<Mutex<ServerStatus>>) -> anyhow::Result<()> {
+    let server_status = &mut *server_info.lock_or_recover();
+    if let ServerType::Master(ref mut master_status) = server_status.server_type {
+        let mut command_bytes = vec![];
+        Resp::from(command.clone()).write_to(&mut command_bytes)?;
+        master_status.repl_offset += command_bytes.len() as u64;
+        master_status.repl_data_offset = master_status.repl_offset;
+        let limit = server_status.client_output_buffer_limits.replica;
+        queue_to_replicas(master_status, limit, &command_bytes);
+    }
+    Ok(())
+}
+
+/// Only queues, so it is safe to call with the server lock held; replicas over the output limit are disconnected
+fn queue_to_replicas(master_status: &mut MasterStatus, limit: OutputBufferLimit, bytes: &[u8]) {
+    master_status.replicas_data.retain_mut(|replica_data| {
+        let queued = replica_data.queue(bytes.to_vec(), limit);
+        if !queued {
+            println!("dropping replica {} over its output buffer limit", replica_data.id);
+            let _ = replica_data.stream.shutdown(Shutdown::Both);
+        }
+        queued
+    });
+}
+
+fn write_replica_output(mut stream: TcpStream, output: Receiver<Vec<u8>>, output_pending: Arc<AtomicU64>) {
+    for bytes in output {
+        if stream.write_all(&bytes).is_err() {
+            return;
+        }
+        output_pending.fetch_sub(bytes.len() as u64, Ordering::Relaxed);
+    }
+}
+
 fn handle_replica_commands(
     stream: TcpStream,
     server_info: Arc<Mutex<ServerStatus>>,
-    replica_index: usize,
+    replica_id: u64,
 ) -> anyhow::Result<()> {
+    let mut buf_reader = BufReader::new(stream.try_clone()?);
+    // Bytes read but not yet parsed, so an ack split across reads is completed by later reads
+    let mut pending = Vec::new();
+    let mut scanner = FrameScanner::default();
     loop {
-        let mut buf_reader = BufReader::new(stream.try_clone()?);
         let bytes = buf_reader.fill_buf()?;
         if bytes.is_empty() {
+            if let ServerType::Master(state) = &mut server_info.lock_or_recover().server_type {
+                state.replicas_data.retain(|replica_data| replica_data.id != replica_id);
+            }
             return Ok(());
         }
+        pending.extend_from_slice(bytes);
+        let read_bytes = bytes.len();
+        buf_reader.consume(read_bytes);
 
-        let remainder = match tokenize_bytes(bytes) {
-            Ok((remainder, tokens)) => {
-                println!("received from replica: {:?}", tokens);
-                let command: RedisCommands = tokens.try_into()?;
-                if let RedisCommands::ReplConf(commands::ReplConfMode::Ack(offset)) = command {
-                    if let ServerType::Master(state) = &mut server_info.lock().unwrap().server_type {
-                        if let Some(replica_data) = state.replicas_data.get_mut(replica_index) {
-                            replica_data.latest_offset = offset as u64;
-                        }
+        let mut consumed_bytes = 0;
+        while consumed_bytes < pending.len() {
+            let bytes = &pending[consumed_bytes..];
+            let tokenized = scanner
+                .scan(bytes)
+                .and_then(|frame_len| Ok((frame_len, tokenize_bytes(&bytes[..frame_len])?.1)));
+            let (frame_len, tokens) = match tokenized {
+                Ok(parsed) => parsed,
+                Err(err) if err.is::<IncompleteFrame>() => break,
+                Err(err) => {
+                    // Closing the link makes the writer thread stop, so the master drops this replica
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return Err(err.context("replica sent an untokenizable frame"));
+                }
+            };
+            consumed_bytes += frame_len;
+            println!("received from replica: {:?}", tokens);
+            let command: RedisCommands = tokens.try_into()?;
+            if let RedisCommands::ReplConf(commands::ReplConfMode::Ack(offset)) = command {
+                if let ServerType::Master(state) = &mut server_info.lock_or_recover().server_type {
+                    if let Some(replica_data) = state.replicas_data.iter_mut().find(|data| data.id == replica_id) {
+                        replica_data.latest_offset = offset as u64;
                     }
                 }
-                remainder
-            }
-            Err(err) => {
-                println!("skip buffer since untokenizable: {}", err);
-                bytes
             }
-        };
-        let consumed_bytes = bytes.len() - remainder.len();
-        buf_reader.consume(consumed_bytes);
+        }
+        pending.drain(..consumed_bytes);
+    }
+}
+
+/// Parses a byte count with an optional `k`/`kb`/`m`/`mb`/`g`/`gb` unit, as Redis config files allow
+fn parse_memory(text: &str) -> anyhow::Result<u64> {
+    let text = text.to_ascii_lowercase();
+    let digits_end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(digits_end);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("invalid memory unit \"{unit}\"")),
+    };
+    let number = number
+        .parse::<u64>()
+        .with_context(|| "memory is not a positive number")?;
+    number
+        .checked_mul(multiplier)
+        .ok_or(anyhow!("memory \"{text}\" is too large"))
+}
+
+fn bytes_to_human(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", size, UNITS[unit])
+}
+
+fn generate_repl_id() -> String {
+    let random_state = RandomState::new();
+    let repl_id: String = (0..3).map(|i| format!("{:016x}", random_state.hash_one(i))).collect();
+    repl_id[..40].to_string()
+}
+
+/// XORs the SHA1 of `data` into `digest`, so the result does not depend on the order of the calls
+fn xor_digest(digest: &mut [u8; 20], data: &[u8]) {
+    for (byte, hash_byte) in digest.iter_mut().zip(sha1(data)) {
+        *byte ^= hash_byte;
     }
 }
 
+/// Like `xor_digest`, then hashes the digest itself so that the order of the calls matters
+fn mix_digest(digest: &mut [u8; 20], data: &[u8]) {
+    xor_digest(digest, data);
+    *digest = sha1(digest);
+}
+
+/// Mixes in a value the way Redis's xorObjectDigest does: its type, its contents and whether it has a TTL.
+/// The deadline itself is left out since a master and its replicas stamp keys at slightly different moments
+fn mix_object_digest(digest: &mut [u8; 20], value: &Value) {
+    const OBJ_STRING: u32 = 0;
+    mix_digest(digest, &OBJ_STRING.to_be_bytes());
+    mix_digest(digest, &value.value);
+    if value.expire.is_some() {
+        xor_digest(digest, b"!!expire!!");
+    }
+}
+
+/// DEBUG DIGEST of the keyspace: all zeros when empty, otherwise independent of the order keys are stored in
+fn dataset_digest(redis_map: &HashMap<Vec<u8>, Value>) -> [u8; 20] {
+    let mut entries = redis_map.iter().filter(|(_, value)| !value.is_expired()).peekable();
+    let mut digest = [0u8; 20];
+    if entries.peek().is_none() {
+        return digest;
+    }
+    let db_index: u32 = 0;
+    mix_digest(&mut digest, &db_index.to_be_bytes());
+    for (key, value) in entries {
+        let mut key_digest = [0u8; 20];
+        mix_digest(&mut key_digest, key);
+        mix_object_digest(&mut key_digest, value);
+        for (byte, key_byte) in digest.iter_mut().zip(key_digest) {
+            *byte ^= key_byte;
+        }
+    }
+    digest
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
     (0..s.len())
         .step_by(2)
         .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::SetOptions;
+    use std::io::Read;
+
+    /// Sums the estimated size of every entry, the value `used_memory` tracks incrementally
+    fn compute_used_memory(redis_map: &HashMap<Vec<u8>, Value>) -> u64 {
+        redis_map.iter().map(|(key, value)| value.estimated_size(key)).sum()
+    }
+
+    fn empty_keyspace() -> Arc<Mutex<Keyspace>> {
+        Arc::new(Mutex::new(Keyspace::default()))
+    }
+
+    fn master_server_info() -> Arc<Mutex<ServerStatus>> {
+        server_info_with(ServerType::Master(MasterStatus {
+            repl_id: generate_repl_id(),
+            repl_id2: "0".repeat(40),
+            second_repl_offset: -1,
+            repl_offset: 0,
+            repl_data_offset: 0,
+            replicas_data: Vec::new(),
+        }))
+    }
+
+    fn replica_server_info(repl_offset: u64) -> Arc<Mutex<ServerStatus>> {
+        server_info_with(ServerType::Replica(ReplicaStatus {
+            master_address: "localhost".to_string(),
+            master_port: 6379,
+            master_replid: generate_repl_id(),
+            repl_offset,
+            master_stream: None,
+        }))
+    }
+
+    fn server_info_with(server_type: ServerType) -> Arc<Mutex<ServerStatus>> {
+        Arc::new(Mutex::new(ServerStatus {
+            server_type,
+            clients: HashMap::new(),
+            blocked_clients: 0,
+            dir: None,
+            db_filename: None,
+            maxclients: DEFAULT_MAXCLIENTS,
+            maxmemory: 0,
+            client_output_buffer_limits: ClientOutputBufferLimits::default(),
+        }))
+    }
+
+    /// Tokenizes a raw request, runs it and returns the raw reply
+    fn run(request: &[u8], redis_map: &Arc<Mutex<Keyspace>>, server_info: &Arc<Mutex<ServerStatus>>) -> Vec<u8> {
+        let (remainder, resp) = tokenize_bytes(request).unwrap();
+        assert!(remainder.is_empty());
+        let command = RedisCommands::try_from(resp).unwrap();
+        let mut reply = vec![];
+        handle_command(&command, &mut reply, 0, redis_map, server_info).unwrap();
+        reply
+    }
+
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (client, server)
+    }
+
+    /// Serves a new connection with `handle_client` on its own thread, returning the client end of it
+    fn spawn_client(
+        redis_map: &Arc<Mutex<Keyspace>>,
+        server_info: &Arc<Mutex<ServerStatus>>,
+    ) -> (TcpStream, thread::JoinHandle<anyhow::Result<()>>) {
+        let (client, server) = socket_pair();
+        let client_guard = ClientGuard::register(1, server_info.clone()).unwrap();
+        let (redis_map, server_info) = (redis_map.clone(), server_info.clone());
+        let handler = thread::spawn(move || handle_client(server, client_guard, redis_map, server_info));
+        (client, handler)
+    }
+
+    /// Registers a replica on a master and returns the socket end the replica would read the stream from
+    fn attach_replica(server_info: &Arc<Mutex<ServerStatus>>) -> TcpStream {
+        let (replica, master_end) = socket_pair();
+        let ServerType::Master(master_status) = &mut server_info.lock_or_recover().server_type else {
+            panic!("not a master");
+        };
+        let replica_data = ReplicaData::new(7, master_end, master_status.repl_offset).unwrap();
+        master_status.replicas_data.push(replica_data);
+        replica
+    }
+
+    fn repl_offset(server_info: &Arc<Mutex<ServerStatus>>) -> u64 {
+        match &server_info.lock_or_recover().server_type {
+            ServerType::Master(master_status) => master_status.repl_offset,
+            ServerType::Replica(replica_status) => replica_status.repl_offset,
+        }
+    }
+
+    fn read_reply(stream: &mut TcpStream) -> Vec<u8> {
+        let mut reply = vec![0; 4096];
+        let read = stream.read(&mut reply).unwrap();
+        reply.truncate(read);
+        reply
+    }
+
+    /// Reads until the replies end with `last`, for replies that may arrive over several reads
+    fn read_replies_until(stream: &mut TcpStream, last: &[u8]) -> Vec<u8> {
+        let mut replies = vec![];
+        while !replies.ends_with(last) {
+            let reply = read_reply(stream);
+            assert!(
+                !reply.is_empty(),
+                "connection closed after {:?}",
+                String::from_utf8_lossy(&replies)
+            );
+            replies.extend(reply);
+        }
+        replies
+    }
+
+    #[test]
+    fn set_and_get_non_utf8_key_and_value() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+
+        let reply = run(
+            b"*3\r\n$3\r\nSET\r\n$2\r\n\xff\xfe\r\n$3\r\n\x00\x80v\r\n",
+            &redis_map,
+            &server_info,
+        );
+        assert_eq!(reply, b"+OK\r\n");
+
+        let reply = run(b"*2\r\n$3\r\nGET\r\n$2\r\n\xff\xfe\r\n", &redis_map, &server_info);
+        assert_eq!(reply, b"$3\r\n\x00\x80v\r\n");
+
+        // The lossy UTF-8 form of the key must not alias it
+        let reply = run(
+            "*2\r\n$3\r\nGET\r\n$6\r\n\u{fffd}\u{fffd}\r\n".as_bytes(),
+            &redis_map,
+            &server_info,
+        );
+        assert_eq!(reply, b"$-1\r\n");
+    }
+
+    #[test]
+    fn used_memory_matches_keyspace_after_mixed_writes() {
+        let redis_map = empty_keyspace();
+        let set = |key: &str, value: &str, expire: Option<u64>| {
+            let command = RedisCommands::Set(SetOptions {
+                key: key.into(),
+                value: value.into(),
+                expire,
+            });
+            apply_write_command(&command, &mut redis_map.lock_or_recover()).unwrap();
+        };
+
+        set("a", "short", None);
+        set("b", &"x".repeat(100), Some(10_000));
+        set("a", &"y".repeat(50), None);
+        set("b", "", None);
+        set("c", "42", Some(1));
+
+        let keyspace = redis_map.lock_or_recover();
+        assert_eq!(keyspace.used_memory, compute_used_memory(&keyspace.entries));
+    }
+
+    fn value(value: &[u8]) -> Value {
+        Value {
+            value: value.to_vec(),
+            expire: None,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn encoding_switches_to_raw_past_embstr_limit() {
+        assert_eq!(value(&[b'a'; OBJ_ENCODING_EMBSTR_SIZE_LIMIT]).encoding(), "embstr");
+        assert_eq!(value(&[b'a'; OBJ_ENCODING_EMBSTR_SIZE_LIMIT + 1]).encoding(), "raw");
+        assert_eq!(value(b"12345").encoding(), "int");
+        assert_eq!(value(b"012").encoding(), "embstr");
+    }
+
+    #[test]
+    fn object_unknown_subcommand_is_an_error_reply() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        run(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n", &redis_map, &server_info);
+
+        let reply = run(
+            b"*3\r\n$6\r\nOBJECT\r\n$4\r\nFREQ\r\n$1\r\nk\r\n",
+            &redis_map,
+            &server_info,
+        );
+        assert_eq!(reply, b"-ERR unknown subcommand 'FREQ'\r\n");
+    }
+
+    #[test]
+    fn command_docs_get_has_summary() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+
+        let reply = run(
+            b"*3\r\n$7\r\nCOMMAND\r\n$4\r\nDOCS\r\n$3\r\nget\r\n",
+            &redis_map,
+            &server_info,
+        );
+        let (_, Resp::Array(docs)) = tokenize_bytes(&reply).unwrap() else {
+            panic!("COMMAND DOCS must reply with an array");
+        };
+        let [Resp::BulkString(name), Resp::Array(fields)] = docs.as_slice() else {
+            panic!("expected one name/doc pair, got {:?}", docs);
+        };
+        assert_eq!(name, b"get");
+        let summary = fields
+            .chunks(2)
+            .find(|field| field[0] == Resp::BulkString(b"summary".to_vec()))
+            .map(|field| &field[1]);
+        assert_eq!(
+            summary,
+            Some(&Resp::BulkString(b"Returns the string value of a key.".to_vec()))
+        );
+    }
+
+    fn keyspace_of(entries: &[(&[u8], &[u8], Option<u64>)]) -> HashMap<Vec<u8>, Value> {
+        let mut redis_map = HashMap::new();
+        for (key, value, expire) in entries {
+            redis_map.insert(
+                key.to_vec(),
+                Value {
+                    value: value.to_vec(),
+                    expire: *expire,
+                    timestamp: SystemTime::now(),
+                },
+            );
+        }
+        redis_map
+    }
+
+    #[test]
+    fn dataset_digest_ignores_insertion_order() {
+        let entries: [(&[u8], &[u8], Option<u64>); 4] = [
+            (b"a", b"1", None),
+            (b"b", b"two", Some(60_000)),
+            (b"c", b"", None),
+            (b"\xff", b"\x00\x01", None),
+        ];
+        let forward = keyspace_of(&entries);
+        let mut reversed_entries = entries;
+        reversed_entries.reverse();
+        let reversed = keyspace_of(&reversed_entries);
+        assert_eq!(dataset_digest(&forward), dataset_digest(&reversed));
+
+        assert_eq!(dataset_digest(&HashMap::new()), [0u8; 20]);
+        let swapped = keyspace_of(&[(b"a", b"two", Some(60_000)), (b"b", b"1", None)]);
+        let original = keyspace_of(&[(b"a", b"1", None), (b"b", b"two", Some(60_000))]);
+        assert_ne!(dataset_digest(&swapped), dataset_digest(&original));
+        let without_ttl = keyspace_of(&[(b"a", b"1", None), (b"b", b"two", None)]);
+        assert_ne!(dataset_digest(&without_ttl), dataset_digest(&original));
+    }
+
+    #[test]
+    fn parse_memory_rejects_overflow() {
+        assert_eq!(parse_memory("64mb").unwrap(), 64 * 1024 * 1024);
+        assert_eq!(parse_memory("1G").unwrap(), 1000 * 1000 * 1000);
+        assert!(parse_memory("99999999999gb").is_err());
+        assert!(parse_memory("18446744073709551616").is_err());
+
+        let mut limits = ClientOutputBufferLimits::default();
+        assert!(limits.set_from_config("replica 99999999999gb 0 0").is_err());
+    }
+
+    #[test]
+    fn config_get_ignores_parameter_case() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+
+        let reply = run(
+            b"*3\r\n$6\r\nCONFIG\r\n$3\r\nGET\r\n$9\r\nMAXMEMORY\r\n",
+            &redis_map,
+            &server_info,
+        );
+        assert_eq!(reply, b"*2\r\n$9\r\nmaxmemory\r\n$1\r\n0\r\n");
+    }
+
+    #[test]
+    fn replicaof_accepts_one_or_two_arguments() {
+        let mut split = ["localhost", "6380", "--port"].map(String::from).into_iter();
+        let mut joined = ["localhost 6380", "--port"].map(String::from).into_iter();
+        let expected = ("localhost".to_string(), 6380);
+        assert_eq!(parse_replicaof(&mut split).unwrap(), expected);
+        assert_eq!(parse_replicaof(&mut joined).unwrap(), expected);
+        assert_eq!(split.next().as_deref(), Some("--port"));
+        assert_eq!(joined.next().as_deref(), Some("--port"));
+
+        assert!(parse_replicaof(&mut ["localhost notaport"].map(String::from).into_iter()).is_err());
+        assert!(parse_replicaof(&mut ["localhost"].map(String::from).into_iter()).is_err());
+    }
+
+    #[test]
+    fn unsupported_commands_reply_with_errors() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+
+        let reply = run(
+            b"*4\r\n$6\r\nCONFIG\r\n$3\r\nSET\r\n$9\r\nmaxmemory\r\n$1\r\n1\r\n",
+            &redis_map,
+            &server_info,
+        );
+        assert_eq!(reply, b"-ERR CONFIG SET is not supported\r\n");
+
+        server_info.lock_or_recover().server_type = ServerType::Replica(ReplicaStatus {
+            master_address: "localhost".to_string(),
+            master_port: 6379,
+            master_replid: String::new(),
+            repl_offset: 0,
+            master_stream: None,
+        });
+        server_info.lock_or_recover().dir = Some(PathBuf::from("/tmp"));
+        let reply = run(
+            b"*3\r\n$6\r\nCONFIG\r\n$3\r\nGET\r\n$3\r\ndir\r\n",
+            &redis_map,
+            &server_info,
+        );
+        assert_eq!(reply, b"*2\r\n$3\r\ndir\r\n$4\r\n/tmp\r\n");
+    }
+
+    #[test]
+    fn panicking_command_does_not_poison_keyspace() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+
+        let crashing_connection = {
+            let redis_map = redis_map.clone();
+            thread::spawn(move || {
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    let _redis_map = redis_map.lock_or_recover();
+                    panic!("command bug while holding the keyspace lock");
+                }))
+            })
+        };
+        assert!(crashing_connection.join().unwrap().is_err());
+        assert!(redis_map.is_poisoned());
+
+        let reply = run(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n", &redis_map, &server_info);
+        assert_eq!(reply, b"+OK\r\n");
+        assert!(!redis_map.is_poisoned());
+        let reply = run(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n", &redis_map, &server_info);
+        assert_eq!(reply, b"$1\r\nv\r\n");
+    }
+
+    #[test]
+    fn truncated_frame_is_dropped_when_the_client_closes() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        let (mut client, handler) = spawn_client(&redis_map, &server_info);
+        client.write_all(b"*1\r\n$4\r\nPING\r\n*3\r\n$3\r\nSET\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), b"+PONG\r\n");
+
+        drop(client);
+        assert!(handler.join().unwrap().is_ok());
+        assert!(redis_map.lock_or_recover().entries.is_empty());
+        assert!(server_info.lock_or_recover().clients.is_empty());
+    }
+
+    #[test]
+    fn expired_keys_give_their_memory_back() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        server_info.lock_or_recover().maxmemory = 500;
+        let set_px = |key: &str| {
+            let request = format!("*5\r\n$3\r\nSET\r\n$2\r\n{key}\r\n$1\r\nv\r\n$2\r\nPX\r\n$2\r\n50\r\n");
+            run(request.as_bytes(), &redis_map, &server_info)
+        };
+
+        for i in 0..10 {
+            set_px(&format!("k{i}"));
+        }
+        assert!(set_px("k9").starts_with(b"-OOM"));
+
+        thread::sleep(Duration::from_millis(60));
+        // Keys nobody reads again are deleted once a write would be refused for them
+        assert_eq!(set_px("k0"), b"+OK\r\n");
+        assert_eq!(redis_map.lock_or_recover().entries.len(), 1);
+
+        thread::sleep(Duration::from_millis(60));
+        // Reading an expired key deletes it
+        assert_eq!(
+            run(b"*2\r\n$3\r\nGET\r\n$2\r\nk0\r\n", &redis_map, &server_info),
+            b"$-1\r\n"
+        );
+        let keyspace = redis_map.lock_or_recover();
+        assert!(keyspace.entries.is_empty());
+        assert_eq!(keyspace.used_memory, compute_used_memory(&keyspace.entries));
+    }
+
+    #[test]
+    fn applying_a_non_write_command_is_an_error() {
+        let redis_map = empty_keyspace();
+        assert!(apply_write_command(&RedisCommands::Get(b"key".to_vec()), &mut redis_map.lock_or_recover()).is_err());
+        assert!(redis_map.lock_or_recover().entries.is_empty());
+    }
+
+    #[test]
+    fn only_write_commands_are_propagated() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        let mut replica = attach_replica(&server_info);
+
+        let requests: [&[u8]; 11] = [
+            b"*1\r\n$4\r\nPING\r\n",
+            b"*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n",
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n",
+            b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n",
+            b"*2\r\n$4\r\nINFO\r\n$11\r\nreplication\r\n",
+            b"*3\r\n$6\r\nCONFIG\r\n$3\r\nGET\r\n$3\r\ndir\r\n",
+            b"*3\r\n$6\r\nOBJECT\r\n$8\r\nENCODING\r\n$1\r\nk\r\n",
+            b"*2\r\n$6\r\nCLIENT\r\n$7\r\nGETNAME\r\n",
+            b"*2\r\n$5\r\nDEBUG\r\n$6\r\nDIGEST\r\n",
+            b"*3\r\n$7\r\nCOMMAND\r\n$4\r\nDOCS\r\n$3\r\nGET\r\n",
+            b"*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nw\r\n$2\r\nEX\r\n$2\r\n10\r\n",
+        ];
+        for request in requests {
+            let command = RedisCommands::try_from(tokenize_bytes(request).unwrap().1).unwrap();
+            let offset = repl_offset(&server_info);
+            run(request, &redis_map, &server_info);
+            let propagated = repl_offset(&server_info) - offset;
+            if command.is_write() {
+                let mut stream_bytes = vec![0; propagated as usize];
+                replica.read_exact(&mut stream_bytes).unwrap();
+                assert_eq!(stream_bytes, Resp::from(command).encode_to_bytes());
+            } else {
+                assert_eq!(propagated, 0, "{:?}", command);
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_writes_reach_replicas_in_applied_order() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        let mut replica = attach_replica(&server_info);
+
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let (redis_map, server_info) = (redis_map.clone(), server_info.clone());
+                thread::spawn(move || {
+                    for i in 0..500 {
+                        // Every writer sets the same keys in turn, so each key ends on a contended write
+                        let request = format!("*3\r\n$3\r\nSET\r\n$4\r\nk{i:03}\r\n$1\r\n{writer}\r\n");
+                        run(request.as_bytes(), &redis_map, &server_info);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let mut stream_bytes = vec![0; repl_offset(&server_info) as usize];
+        replica.read_exact(&mut stream_bytes).unwrap();
+        let mut remainder = &stream_bytes[..];
+        let mut replayed = HashMap::new();
+        while !remainder.is_empty() {
+            let (new_remainder, resp) = tokenize_bytes(remainder).unwrap();
+            if let Ok(RedisCommands::Set(options)) = RedisCommands::try_from(resp) {
+                replayed.insert(options.key, options.value);
+            }
+            remainder = new_remainder;
+        }
+        let keyspace = redis_map.lock_or_recover();
+        assert_eq!(replayed.len(), keyspace.entries.len());
+        for (key, value) in replayed {
+            assert_eq!(value, keyspace.entries[&key].value, "{}", String::from_utf8_lossy(&key));
+        }
+    }
+
+    #[test]
+    fn protocol_errors_skip_only_the_bad_frame() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        let (mut client, handler) = spawn_client(&redis_map, &server_info);
+
+        // A RESP3 set split across reads: its elements must not be read as commands once it completes
+        client.write_all(b"~2\r\n+a\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"+b\r\n*1\r\n$4\r\nPING\r\n").unwrap();
+        assert_eq!(
+            read_replies_until(&mut client, b"+PONG\r\n"),
+            b"-ERR Protocol error: unsupported RESP type '~'\r\n+PONG\r\n"
+        );
+
+        // Pipelined commands after a malformed header still run
+        client.write_all(b"*1\r\n$abc\r\n*1\r\n$4\r\nPING\r\n").unwrap();
+        assert_eq!(
+            read_replies_until(&mut client, b"+PONG\r\n"),
+            b"-ERR Protocol error: invalid bulk length\r\n+PONG\r\n"
+        );
+
+        drop(client);
+        assert!(handler.join().unwrap().is_ok());
+    }
+
+    /// A replica whose queued output is never written out, so its pending bytes only grow
+    fn stalled_replica() -> (ReplicaData, Receiver<Vec<u8>>) {
+        let (output, output_receiver) = mpsc::channel();
+        let replica_data = ReplicaData {
+            id: 7,
+            stream: socket_pair().1,
+            output,
+            output_pending: Arc::new(AtomicU64::new(0)),
+            soft_limit_reached_at: None,
+            latest_offset: 0,
+        };
+        (replica_data, output_receiver)
+    }
+
+    #[test]
+    fn replica_queue_enforces_output_buffer_limits() {
+        let hard_limit = OutputBufferLimit {
+            hard: 100,
+            soft: 0,
+            soft_seconds: 0,
+        };
+        let (mut replica, _output) = stalled_replica();
+        assert!(replica.queue(vec![0; 99], hard_limit));
+        assert!(!replica.queue(vec![0; 1], hard_limit));
+
+        let soft_limit = OutputBufferLimit {
+            hard: 0,
+            soft: 100,
+            soft_seconds: 60,
+        };
+        let (mut replica, _output) = stalled_replica();
+        assert!(replica.queue(vec![0; 100], soft_limit));
+        assert!(replica.queue(vec![0; 1], soft_limit));
+        // Draining below the soft limit restarts its timer
+        replica.output_pending.store(0, Ordering::Relaxed);
+        assert!(replica.queue(vec![0; 1], soft_limit));
+        assert!(replica.soft_limit_reached_at.is_none());
+
+        assert!(replica.queue(vec![0; 100], soft_limit));
+        replica.soft_limit_reached_at = Some(SystemTime::now() - Duration::from_secs(61));
+        assert!(!replica.queue(vec![0; 1], soft_limit));
+    }
+
+    #[test]
+    fn output_buffer_limits_need_four_words_per_class() {
+        let mut limits = ClientOutputBufferLimits::default();
+        let err = limits.set_from_config("replica 1mb 1mb").unwrap_err();
+        assert!(err.to_string().contains("groups of"));
+        limits.set_from_config("replica 1m 512kb 10").unwrap();
+        assert_eq!(limits.replica.hard, 1_000_000);
+        assert_eq!(limits.replica.soft, 512 * 1024);
+        assert_eq!(limits.replica.soft_seconds, 10);
+    }
+
+    /// Hands out at most two bytes per read, like a master whose transfer arrives in many small segments
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(2);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn rdb_transfer_is_skipped_across_small_reads() {
+        let rdb = decode_hex(EMPTY_RDB).unwrap();
+        let transfer = [
+            format!("${}\r\n", rdb.len()).into_bytes(),
+            rdb.clone(),
+            b"*1\r\n$4\r\nPING\r\n".to_vec(),
+        ]
+        .concat();
+        let mut reader = BufReader::with_capacity(3, Trickle(&transfer));
+        assert_eq!(skip_rdb_transfer(&mut reader).unwrap(), rdb.len());
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"*1\r\n$4\r\nPING\r\n");
+
+        let truncated = [format!("${}\r\n", rdb.len()).into_bytes(), rdb[..10].to_vec()].concat();
+        assert!(skip_rdb_transfer(&mut BufReader::with_capacity(3, Trickle(&truncated))).is_err());
+        assert!(skip_rdb_transfer(&mut BufReader::new(Trickle(b"+OK\r\n"))).is_err());
+    }
+
+    #[test]
+    fn wait_for_zero_replicas_counts_acked_replicas_without_blocking() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        let mut outputs = vec![];
+        if let ServerType::Master(master_status) = &mut server_info.lock_or_recover().server_type {
+            master_status.repl_offset = 100;
+            master_status.repl_data_offset = 100;
+            for latest_offset in [100, 50] {
+                let (mut replica_data, output) = stalled_replica();
+                replica_data.latest_offset = latest_offset;
+                master_status.replicas_data.push(replica_data);
+                outputs.push(output);
+            }
+        }
+
+        let start = SystemTime::now();
+        let reply = run(
+            b"*3\r\n$4\r\nWAIT\r\n$1\r\n0\r\n$3\r\n100\r\n",
+            &redis_map,
+            &server_info,
+        );
+        assert_eq!(reply, b":1\r\n");
+        assert!(start.elapsed().unwrap() < Duration::from_millis(100));
+        // No GETACK was sent to refresh the offsets
+        assert_eq!(repl_offset(&server_info), 100);
+        assert!(outputs.iter().all(|output| output.try_recv().is_err()));
+    }
+
+    #[test]
+    fn wait_counts_a_replica_that_just_full_synced() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        let reply = run(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n", &redis_map, &server_info);
+        assert_eq!(reply, b"+OK\r\n");
+
+        let offset = repl_offset(&server_info);
+        let (mut replica, mut master_end) = socket_pair();
+        assert!(start_full_resync(&mut master_end, 7, &server_info).unwrap());
+        let fullresync = String::from_utf8_lossy(&read_reply(&mut replica)).into_owned();
+        assert!(fullresync.starts_with("+FULLRESYNC "));
+        assert!(fullresync.contains(&format!(" {offset}\r\n")));
+
+        // The resync covers the SET, so the replica counts without acking it
+        let start = SystemTime::now();
+        let reply = run(
+            b"*3\r\n$4\r\nWAIT\r\n$1\r\n1\r\n$4\r\n5000\r\n",
+            &redis_map,
+            &server_info,
+        );
+        assert_eq!(reply, b":1\r\n");
+        assert!(start.elapsed().unwrap() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn replicas_ack_their_offset_without_getack() {
+        let master_info = master_server_info();
+        let (replica_end, master_end) = socket_pair();
+        if let ServerType::Master(master_status) = &mut master_info.lock_or_recover().server_type {
+            let replica_data = ReplicaData::new(7, master_end.try_clone().unwrap(), 0).unwrap();
+            master_status.replicas_data.push(replica_data);
+        }
+        let link_info = master_info.clone();
+        thread::spawn(move || handle_replica_commands(master_end, link_info, 7));
+
+        let replica_info = replica_server_info(42);
+        let ack_info = replica_info.clone();
+        thread::spawn(move || send_periodic_acks(replica_end, ack_info));
+
+        let latest_offset = || match &master_info.lock_or_recover().server_type {
+            ServerType::Master(master_status) => master_status.replicas_data[0].latest_offset,
+            ServerType::Replica(_) => unreachable!(),
+        };
+        let deadline = SystemTime::now() + REPLICA_ACK_PERIOD * 3;
+        while latest_offset() != 42 {
+            assert!(
+                SystemTime::now() < deadline,
+                "no ACK within {:?}",
+                REPLICA_ACK_PERIOD * 3
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(repl_offset(&master_info), 0);
+    }
+
+    #[test]
+    fn client_setname_rejects_spaces_and_round_trips_through_getname() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        let _client_guard = ClientGuard::register(0, server_info.clone()).unwrap();
+        let getname = b"*2\r\n$6\r\nCLIENT\r\n$7\r\nGETNAME\r\n";
+
+        let reply = run(
+            b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$5\r\na b c\r\n",
+            &redis_map,
+            &server_info,
+        );
+        assert!(reply.starts_with(b"-ERR Client names cannot contain spaces"));
+        assert_eq!(run(getname, &redis_map, &server_info), b"$0\r\n\r\n");
+
+        let reply = run(
+            b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$8\r\nworker-1\r\n",
+            &redis_map,
+            &server_info,
+        );
+        assert_eq!(reply, b"+OK\r\n");
+        assert_eq!(run(getname, &redis_map, &server_info), b"$8\r\nworker-1\r\n");
+    }
+
+    #[test]
+    fn empty_arrays_and_blank_lines_get_no_reply() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        let (mut client, handler) = spawn_client(&redis_map, &server_info);
+
+        client.write_all(b"*0\r\n\r\n*0\r\n*1\r\n$4\r\nPING\r\n").unwrap();
+        assert_eq!(read_replies_until(&mut client, b"+PONG\r\n"), b"+PONG\r\n");
+        client.write_all(b"*0\r\n").unwrap();
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        assert_eq!(read_replies_until(&mut client, b"+PONG\r\n"), b"+PONG\r\n");
+
+        drop(client);
+        assert!(handler.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn config_get_unknown_parameter_is_empty() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+
+        let reply = run(
+            b"*3\r\n$6\r\nCONFIG\r\n$3\r\nGET\r\n$19\r\ntotallyunknownparam\r\n",
+            &redis_map,
+            &server_info,
+        );
+        assert_eq!(reply, b"*0\r\n");
+    }
+
+    #[test]
+    fn client_idle_time_grows_until_touched() {
+        let redis_map = empty_keyspace();
+        let server_info = master_server_info();
+        let client_guard = ClientGuard::register(0, server_info.clone()).unwrap();
+        let client_info = b"*2\r\n$6\r\nCLIENT\r\n$4\r\nINFO\r\n";
+
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        if let Some(client) = server_info.lock_or_recover().clients.get_mut(&0) {
+            client.created_at = an_hour_ago;
+            client.last_interaction = an_hour_ago;
+        }
+        let reply = run(client_info, &redis_map, &server_info);
+        assert!(String::from_utf8(reply).unwrap().contains(" age=3600 idle=3600\n"));
+
+        client_guard.touch();
+        let reply = run(client_info, &redis_map, &server_info);
+        assert!(String::from_utf8(reply).unwrap().contains(" age=3600 idle=0\n"));
+    }
+
+    #[test]
+    fn client_slots_are_capped_at_maxclients_and_freed_on_drop() {
+        let server_info = master_server_info();
+        server_info.lock_or_recover().maxclients = 2;
+
+        let first = ClientGuard::register(1, server_info.clone()).unwrap();
+        let _second = ClientGuard::register(2, server_info.clone()).unwrap();
+        assert!(ClientGuard::register(3, server_info.clone()).is_none());
+        assert!(!server_info.lock_or_recover().clients.contains_key(&3));
+
+        drop(first);
+        assert!(ClientGuard::register(3, server_info.clone()).is_some());
+    }
+}
@@ -1,69 +1,122 @@
-use std::backtrace::Backtrace;
+use std::{fmt, io::Write};
 
 use anyhow::anyhow;
 
-#[derive(Debug, PartialEq, Eq)]
+/// Largest bulk payload accepted from a peer, matching Redis's default `proto-max-bulk-len`
+const PROTO_MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+const PROTO_MAX_MULTIBULK_LEN: i64 = i32::MAX as i64;
+/// Deepest aggregate nesting accepted from a peer. Each level is one recursive call, so a run of `*1\r\n`
+/// headers would otherwise overflow the stack, which no `catch_unwind` can recover from
+const MAX_NESTING_DEPTH: usize = 128;
+
+/// The buffer ends before the frame does; more bytes from the peer may still complete it
+#[derive(Debug)]
+pub struct IncompleteFrame;
+
+impl fmt::Display for IncompleteFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "incomplete RESP frame")
+    }
+}
+
+impl std::error::Error for IncompleteFrame {}
+
+#[derive(PartialEq, Eq)]
 pub enum Resp {
     Array(Vec<Resp>),
-    BulkString(String),
+    /// Binary-safe payload, keys and values may hold any bytes
+    BulkString(Vec<u8>),
+    VerbatimString(String, String),
     SimpleString(String),
+    SimpleError(String),
     Integer(i64),
     NullBulkString,
+    NullArray,
+    Attribute(Vec<(Resp, Resp)>, Box<Resp>),
     Empty,
 }
 
-impl Resp {
-    pub fn encode_to_string(&self) -> String {
+impl fmt::Debug for Resp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Resp::Array(vector) => {
-                let mut encoded = format!("*{}\r\n", vector.len());
-                for val in vector {
-                    encoded += &val.encode_to_string()
-                }
-                encoded
+            Resp::Array(vector) => f.debug_tuple("Array").field(vector).finish(),
+            Resp::BulkString(bytes) => f
+                .debug_tuple("BulkString")
+                .field(&String::from_utf8_lossy(bytes))
+                .finish(),
+            Resp::VerbatimString(format, string) => {
+                f.debug_tuple("VerbatimString").field(format).field(string).finish()
             }
-            Resp::BulkString(string) => format!("${}\r\n{}\r\n", string.len(), string),
-            Resp::SimpleString(string) => format!("+{}\r\n", string),
-            Resp::Integer(num) => format!(":{}\r\n", num),
-            Resp::NullBulkString => "$-1\r\n".to_string(),
-            Resp::Empty => String::new(),
+            Resp::SimpleString(string) => f.debug_tuple("SimpleString").field(string).finish(),
+            Resp::SimpleError(string) => f.debug_tuple("SimpleError").field(string).finish(),
+            Resp::Integer(num) => f.debug_tuple("Integer").field(num).finish(),
+            Resp::NullBulkString => f.write_str("NullBulkString"),
+            Resp::NullArray => f.write_str("NullArray"),
+            Resp::Attribute(attributes, value) => f.debug_tuple("Attribute").field(attributes).field(value).finish(),
+            Resp::Empty => f.write_str("Empty"),
         }
     }
+}
 
+impl Resp {
     pub fn encode_to_bytes(&self) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        self.write_to(&mut encoded).expect("writing into a Vec cannot fail");
+        encoded
+    }
+
+    /// Encodes straight into `out`, without building the whole frame in memory first
+    pub fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
         match self {
             Resp::Array(vector) => {
-                let mut encoded = [b"*", vector.len().to_string().as_bytes(), b"\r\n"].concat();
+                write!(out, "*{}\r\n", vector.len())?;
                 for val in vector {
-                    encoded = [encoded, val.encode_to_bytes()].concat();
+                    val.write_to(out)?;
                 }
-                encoded
+                Ok(())
             }
-            Resp::BulkString(string) => [
-                b"$",
-                string.len().to_string().as_bytes(),
-                b"\r\n",
-                string.as_bytes(),
-                b"\r\n",
-            ]
-            .concat(),
-            Resp::SimpleString(string) => [b"+", string.as_bytes(), b"\r\n"].concat(),
-            Resp::Integer(num) => [b":", num.to_string().as_bytes(), b"\r\n"].concat(),
-            Resp::NullBulkString => b"$-1\r\n".to_vec(),
-            Resp::Empty => vec![],
+            Resp::BulkString(bytes) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Resp::VerbatimString(format, string) => {
+                write!(out, "={}\r\n{}:{}\r\n", format.len() + 1 + string.len(), format, string)
+            }
+            Resp::SimpleString(string) => write!(out, "+{}\r\n", string),
+            Resp::SimpleError(string) => write!(out, "-{}\r\n", string),
+            Resp::Integer(num) => write!(out, ":{}\r\n", num),
+            Resp::NullBulkString => out.write_all(b"$-1\r\n"),
+            Resp::NullArray => out.write_all(b"*-1\r\n"),
+            Resp::Attribute(attributes, value) => {
+                write!(out, "|{}\r\n", attributes.len())?;
+                for (key, val) in attributes {
+                    key.write_to(out)?;
+                    val.write_to(out)?;
+                }
+                value.write_to(out)
+            }
+            Resp::Empty => Ok(()),
         }
     }
 }
 
 pub fn tokenize_bytes(buffer: &[u8]) -> anyhow::Result<(&[u8], Resp)> {
-    let value_type = buffer.first().ok_or(anyhow!("RESP type not found"))?;
+    tokenize_nested(buffer, 0)
+}
+
+fn tokenize_nested(buffer: &[u8], depth: usize) -> anyhow::Result<(&[u8], Resp)> {
+    let value_type = buffer.first().ok_or(IncompleteFrame)?;
     match value_type {
         b'*' => {
             let (mut remainder, line_bytes) = read_next_line(buffer)?;
-            let len = String::from_utf8(line_bytes[1..].to_vec())?.parse::<usize>()?;
+            let Some(len) = parse_length(line_bytes, PROTO_MAX_MULTIBULK_LEN, "multibulk")? else {
+                return Ok((remainder, Resp::NullArray));
+            };
+            check_depth(depth)?;
             let mut vec: Vec<Resp> = Vec::new();
             for _ in 0..len {
-                let (new_remainder, child_resp) = tokenize_bytes(remainder)?;
+                let (new_remainder, child_resp) = tokenize_nested(remainder, depth + 1)?;
                 vec.push(child_resp);
                 remainder = new_remainder;
             }
@@ -71,13 +124,21 @@ pub fn tokenize_bytes(buffer: &[u8]) -> anyhow::Result<(&[u8], Resp)> {
         }
         b'$' => {
             let (remainder, line_bytes) = read_next_line(buffer)?;
-            let len = String::from_utf8(line_bytes[1..].to_vec())?.parse::<usize>()?;
-            let (remainder, line_bytes) = read_next_line(remainder)?;
-            let text = String::from_utf8(line_bytes[..].to_vec())?;
-            if len != text.len() {
-                return Err(anyhow!("RESP bulk string len does not coincide"));
-            }
-            Ok((remainder, Resp::BulkString(text.to_owned())))
+            let Some(len) = parse_length(line_bytes, PROTO_MAX_BULK_LEN, "bulk")? else {
+                return Ok((remainder, Resp::NullBulkString));
+            };
+            let (remainder, payload) = read_payload(remainder, len)?;
+            Ok((remainder, Resp::BulkString(payload.to_vec())))
+        }
+        b'=' => {
+            let (remainder, line_bytes) = read_next_line(buffer)?;
+            let len = parse_length(line_bytes, PROTO_MAX_BULK_LEN, "bulk")?.ok_or(anyhow!("invalid bulk length"))?;
+            let (remainder, payload) = read_payload(remainder, len)?;
+            let text = String::from_utf8(payload.to_vec())?;
+            let (format, text) = text
+                .split_once(':')
+                .ok_or(anyhow!("RESP verbatim string format not found"))?;
+            Ok((remainder, Resp::VerbatimString(format.to_owned(), text.to_owned())))
         }
         b':' => {
             let (remainder, line_bytes) = read_next_line(buffer)?;
@@ -89,20 +150,389 @@ pub fn tokenize_bytes(buffer: &[u8]) -> anyhow::Result<(&[u8], Resp)> {
             let text = String::from_utf8(line_bytes[1..].to_vec())?;
             Ok((remainder, Resp::SimpleString(text.to_string())))
         }
-        _ => {
-            println!("RESP type `{}` not implemented", value_type);
-            println!("Custom backtrace: {}", Backtrace::force_capture());
-            unimplemented!()
+        b'-' => {
+            let (remainder, line_bytes) = read_next_line(buffer)?;
+            let text = String::from_utf8(line_bytes[1..].to_vec())?;
+            Ok((remainder, Resp::SimpleError(text.to_string())))
+        }
+        b'|' => {
+            let (mut remainder, line_bytes) = read_next_line(buffer)?;
+            let len = parse_length(line_bytes, PROTO_MAX_MULTIBULK_LEN, "multibulk")?
+                .ok_or(anyhow!("invalid multibulk length"))?;
+            check_depth(depth)?;
+            let mut attributes: Vec<(Resp, Resp)> = Vec::new();
+            for _ in 0..len {
+                let (new_remainder, key) = tokenize_nested(remainder, depth + 1)?;
+                let (new_remainder, val) = tokenize_nested(new_remainder, depth + 1)?;
+                attributes.push((key, val));
+                remainder = new_remainder;
+            }
+            let (remainder, value) = tokenize_nested(remainder, depth + 1)?;
+            Ok((remainder, Resp::Attribute(attributes, Box::new(value))))
         }
+        _ => Err(anyhow!("unsupported RESP type '{}'", *value_type as char)),
+    }
+}
+
+/// A frame with a malformed header, so where it ends is unknown. The next frame is assumed to start right after
+/// the line holding that header
+#[derive(Debug)]
+pub struct MalformedFrame {
+    pub reason: anyhow::Error,
+    pub resume_at: usize,
+}
+
+impl fmt::Display for MalformedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
     }
 }
 
+impl std::error::Error for MalformedFrame {}
+
+/// Finds where the first frame of a growing buffer ends, resuming where the previous call stopped instead of
+/// walking the frame again from its first byte, so a frame that arrives over many reads is scanned once
+#[derive(Default)]
+pub struct FrameScanner {
+    /// Bytes at the start of the buffer that hold complete elements of the frame
+    scanned: usize,
+    /// How far a line that has no `\r\n` yet was already searched
+    line_searched: usize,
+    /// Elements still owed by each enclosing aggregate, innermost last
+    open: Vec<usize>,
+}
+
+impl FrameScanner {
+    /// Returns the length of the first frame in `buffer`, or `IncompleteFrame` until all of it has arrived.
+    /// Between calls the buffer may only grow at its end; once a frame or an error is returned the scanner
+    /// starts over with the next frame
+    pub fn scan(&mut self, buffer: &[u8]) -> anyhow::Result<usize> {
+        let scanned = self.scan_elements(buffer);
+        match &scanned {
+            Err(err) if err.is::<IncompleteFrame>() => {}
+            _ => *self = Self::default(),
+        }
+        scanned
+    }
+
+    fn scan_elements(&mut self, buffer: &[u8]) -> anyhow::Result<usize> {
+        loop {
+            let (line_end, line_bytes) = self.next_line(buffer)?;
+            let element_end = match self.scan_element(buffer, line_end, line_bytes) {
+                Ok(Some(element_end)) => element_end,
+                Ok(None) => continue,
+                Err(err) if err.is::<IncompleteFrame>() => return Err(err),
+                Err(reason) => {
+                    let malformed = MalformedFrame {
+                        reason,
+                        resume_at: line_end,
+                    };
+                    return Err(malformed.into());
+                }
+            };
+            self.scanned = element_end;
+            if self.close_element() {
+                return Ok(self.scanned);
+            }
+        }
+    }
+
+    /// Returns where the element whose header line ends at `line_end` ends, or `None` if it is an aggregate
+    /// whose elements are scanned next. RESP3 types this server does not parse are still delimited, so they
+    /// are rejected as a whole frame instead of their elements being read as commands
+    fn scan_element(&mut self, buffer: &[u8], line_end: usize, line_bytes: &[u8]) -> anyhow::Result<Option<usize>> {
+        let aggregate_len = match line_bytes.first() {
+            Some(b'*' | b'~' | b'>') => parse_length(line_bytes, PROTO_MAX_MULTIBULK_LEN, "multibulk")?,
+            // Every map entry and every attribute is a key and a value; attributes also precede the value
+            // they describe
+            Some(b'%') => parse_length(line_bytes, PROTO_MAX_MULTIBULK_LEN, "multibulk")?.map(|len| 2 * len),
+            Some(b'|') => {
+                let len = parse_length(line_bytes, PROTO_MAX_MULTIBULK_LEN, "multibulk")?
+                    .ok_or(anyhow!("invalid multibulk length"))?;
+                Some(2 * len + 1)
+            }
+            Some(b'$' | b'=' | b'!') => {
+                return match parse_length(line_bytes, PROTO_MAX_BULK_LEN, "bulk")? {
+                    Some(len) => {
+                        let (remainder, _) = read_payload(&buffer[line_end..], len)?;
+                        Ok(Some(buffer.len() - remainder.len()))
+                    }
+                    None => Ok(Some(line_end)),
+                };
+            }
+            // Single-line types; tokenize_bytes checks their contents and rejects unknown types
+            _ => None,
+        };
+        match aggregate_len {
+            Some(len) if len > 0 => {
+                check_depth(self.open.len())?;
+                self.open.push(len);
+                self.scanned = line_end;
+                Ok(None)
+            }
+            _ => Ok(Some(line_end)),
+        }
+    }
+
+    /// Returns the end of the line starting at `scanned`, including its `\r\n`, and the line without it
+    fn next_line<'a>(&mut self, buffer: &'a [u8]) -> anyhow::Result<(usize, &'a [u8])> {
+        let search_from = self.line_searched.max(self.scanned);
+        match buffer[search_from..].windows(2).position(|bytes| bytes == b"\r\n") {
+            Some(idx) => {
+                let line_end = search_from + idx;
+                Ok((line_end + 2, &buffer[self.scanned..line_end]))
+            }
+            None => {
+                // The last byte may be the `\r` of a `\r\n` split across reads, so search it again next time
+                self.line_searched = buffer.len().saturating_sub(1).max(self.scanned);
+                Err(IncompleteFrame.into())
+            }
+        }
+    }
+
+    /// Counts a finished element against its enclosing aggregates, true once it completes the whole frame
+    fn close_element(&mut self) -> bool {
+        while let Some(remaining) = self.open.last_mut() {
+            *remaining -= 1;
+            if *remaining > 0 {
+                return false;
+            }
+            self.open.pop();
+        }
+        true
+    }
+}
+
+fn check_depth(depth: usize) -> anyhow::Result<()> {
+    if depth >= MAX_NESTING_DEPTH {
+        return Err(anyhow!("nesting deeper than {MAX_NESTING_DEPTH} levels"));
+    }
+    Ok(())
+}
+
+/// Parses the length in a `*`/`$`-style header line, where `-1` is the null form and yields `None`
+fn parse_length(line_bytes: &[u8], max_len: i64, kind: &str) -> anyhow::Result<Option<usize>> {
+    let len = std::str::from_utf8(&line_bytes[1..])
+        .ok()
+        .and_then(|text| text.parse::<i64>().ok())
+        .ok_or(anyhow!("invalid {kind} length"))?;
+    match len {
+        -1 => Ok(None),
+        len if (0..=max_len).contains(&len) => Ok(Some(len as usize)),
+        _ => Err(anyhow!("invalid {kind} length")),
+    }
+}
+
+/// Splits off the `len` payload bytes of a bulk frame and the `\r\n` that must follow them
+fn read_payload(buffer: &[u8], len: usize) -> anyhow::Result<(&[u8], &[u8])> {
+    if buffer.len() < len + 2 {
+        return Err(IncompleteFrame.into());
+    }
+    let (payload, remainder) = buffer.split_at(len);
+    let remainder = remainder
+        .strip_prefix(b"\r\n")
+        .ok_or(anyhow!("RESP bulk string len does not coincide"))?;
+    Ok((remainder, payload))
+}
+
 pub fn read_next_line(buffer: &[u8]) -> anyhow::Result<(&[u8], &[u8])> {
-    let (next_rn_idx, next_line_idx) = match buffer.windows(2).position(|bytes| bytes == b"\r\n") {
-        Some(index) => (index, index + 2),
-        None => (buffer.len(), buffer.len()),
-    };
-    let line_bytes = buffer.get(..next_rn_idx).ok_or(anyhow!("RESP next line not found"))?;
-    let remainder = buffer.get(next_line_idx..).ok_or(anyhow!("RESP remainder not found"))?;
-    Ok((remainder, line_bytes))
+    let next_rn_idx = buffer
+        .windows(2)
+        .position(|bytes| bytes == b"\r\n")
+        .ok_or(IncompleteFrame)?;
+    Ok((&buffer[next_rn_idx + 2..], &buffer[..next_rn_idx]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attribute_wrapped_integer_round_trips() {
+        let resp = Resp::Attribute(
+            vec![(Resp::SimpleString("ttl".to_string()), Resp::Integer(3600))],
+            Box::new(Resp::Integer(42)),
+        );
+        let bytes = resp.encode_to_bytes();
+        assert_eq!(bytes, b"|1\r\n+ttl\r\n:3600\r\n:42\r\n");
+
+        let (remainder, decoded) = tokenize_bytes(&bytes).unwrap();
+        assert!(remainder.is_empty());
+        assert_eq!(decoded, resp);
+    }
+
+    fn is_incomplete(buffer: &[u8]) -> bool {
+        tokenize_bytes(buffer).unwrap_err().is::<IncompleteFrame>()
+    }
+
+    #[test]
+    fn malformed_lengths_are_protocol_errors() {
+        for header in [
+            &b"*-5\r\n"[..],
+            b"$-2\r\n",
+            b"$abc\r\n",
+            b"*abc\r\n",
+            b"$\r\n",
+            b"$536870913\r\n",
+            b"*2147483648\r\n",
+            b"$99999999999999999999\r\n",
+        ] {
+            assert!(!is_incomplete(header), "{:?}", String::from_utf8_lossy(header));
+        }
+        assert!(parse_length(b"$-2", PROTO_MAX_BULK_LEN, "bulk").is_err());
+        assert_eq!(parse_length(b"$-1", PROTO_MAX_BULK_LEN, "bulk").unwrap(), None);
+        assert_eq!(
+            parse_length(b"$536870912", PROTO_MAX_BULK_LEN, "bulk").unwrap(),
+            Some(PROTO_MAX_BULK_LEN as usize)
+        );
+    }
+
+    #[test]
+    fn truncated_frames_are_incomplete() {
+        for partial in [
+            &b""[..],
+            b"$5",
+            b"$5\r\nab",
+            b"$5\r\nabcde",
+            b"$5\r\nabcde\r",
+            b"*2\r\n$1\r\na\r\n",
+        ] {
+            assert!(is_incomplete(partial), "{:?}", String::from_utf8_lossy(partial));
+        }
+    }
+
+    #[test]
+    fn payload_must_end_at_its_length() {
+        assert!(read_payload(b"ab", 3).unwrap_err().is::<IncompleteFrame>());
+        let err = read_payload(b"abcdefg\r\n", 5).unwrap_err();
+        assert!(!err.is::<IncompleteFrame>());
+        assert_eq!(read_payload(b"abc\r\nrest", 3).unwrap(), (&b"rest"[..], &b"abc"[..]));
+    }
+
+    #[test]
+    fn deeply_nested_frames_are_rejected() {
+        let nested = |depth: usize| [b"*1\r\n".repeat(depth), b":1\r\n".to_vec()].concat();
+        assert!(tokenize_bytes(&nested(MAX_NESTING_DEPTH)).is_ok());
+        let err = tokenize_bytes(&nested(MAX_NESTING_DEPTH + 1)).unwrap_err();
+        assert!(!err.is::<IncompleteFrame>());
+
+        // Far past the stack limit, the frame must still fail cleanly instead of overflowing
+        assert!(tokenize_bytes(&nested(200_000)).is_err());
+        let attributes = [b"|1\r\n+k\r\n".repeat(200_000), b":1\r\n".to_vec()].concat();
+        assert!(tokenize_bytes(&attributes).is_err());
+        assert!(!FrameScanner::default()
+            .scan(&nested(200_000))
+            .unwrap_err()
+            .is::<IncompleteFrame>());
+    }
+
+    #[test]
+    fn scanner_finds_frames_fed_one_byte_at_a_time() {
+        let frames: [&[u8]; 6] = [
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nv\r\nxy\r\n",
+            b"+OK\r\n",
+            b"*2\r\n*0\r\n*-1\r\n",
+            b"$-1\r\n",
+            b"|1\r\n+ttl\r\n:3600\r\n*1\r\n:42\r\n",
+            b"=7\r\ntxt:a\r\n\r\n",
+        ];
+        let stream = frames.concat();
+        let mut scanner = FrameScanner::default();
+        let mut frame_start = 0;
+        let mut found = Vec::new();
+        for end in 1..=stream.len() {
+            match scanner.scan(&stream[frame_start..end]) {
+                Ok(frame_len) => {
+                    let frame = &stream[frame_start..frame_start + frame_len];
+                    assert!(tokenize_bytes(frame).unwrap().0.is_empty());
+                    found.push(frame);
+                    frame_start += frame_len;
+                }
+                Err(err) => assert!(err.is::<IncompleteFrame>()),
+            }
+        }
+        assert_eq!(found, frames);
+    }
+
+    #[test]
+    fn scanner_delimits_unsupported_and_malformed_frames() {
+        for frame in [
+            &b"~2\r\n+a\r\n+b\r\n"[..],
+            b">2\r\n+a\r\n*1\r\n:1\r\n",
+            b"%1\r\n+k\r\n:1\r\n",
+            b"!3\r\nerr\r\n",
+            b"_\r\n",
+        ] {
+            let pipelined = [frame, b"+next\r\n"].concat();
+            assert_eq!(FrameScanner::default().scan(&pipelined).unwrap(), frame.len());
+            assert!(tokenize_bytes(frame).is_err());
+        }
+
+        let err = FrameScanner::default().scan(b"*2\r\n$abc\r\nfoo\r\n").unwrap_err();
+        let malformed = err.downcast_ref::<MalformedFrame>().unwrap();
+        assert_eq!(malformed.resume_at, b"*2\r\n$abc\r\n".len());
+    }
+
+    /// Keeps what is written and the largest single write, to show frames are written piece by piece
+    #[derive(Default)]
+    struct RecordingWriter {
+        written: Vec<u8>,
+        largest_write: usize,
+    }
+
+    impl Write for RecordingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.largest_write = self.largest_write.max(buf.len());
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn large_arrays_are_written_element_by_element() {
+        let values = (0..10_000).map(|i| format!("value-{i}")).collect::<Vec<_>>();
+        let resp = Resp::Array(
+            values
+                .iter()
+                .map(|value| Resp::BulkString(value.clone().into_bytes()))
+                .collect(),
+        );
+        let mut expected = b"*10000\r\n".to_vec();
+        for value in &values {
+            expected.extend_from_slice(format!("${}\r\n{}\r\n", value.len(), value).as_bytes());
+        }
+
+        let mut writer = RecordingWriter::default();
+        resp.write_to(&mut writer).unwrap();
+        assert_eq!(writer.written, expected);
+        assert!(writer.largest_write <= "value-9999".len());
+        assert_eq!(resp.encode_to_bytes(), expected);
+    }
+
+    #[test]
+    fn nested_arrays_round_trip() {
+        let resp = Resp::Array(vec![
+            Resp::BulkString(b"\x00\xff\r\nbinary".to_vec()),
+            Resp::SimpleString("OK".to_string()),
+            Resp::SimpleError("ERR oops".to_string()),
+            Resp::Integer(-42),
+            Resp::NullBulkString,
+            Resp::NullArray,
+            Resp::Array(vec![]),
+            Resp::Array(vec![Resp::Array(vec![Resp::Integer(1)]), Resp::BulkString(vec![])]),
+            Resp::VerbatimString("txt".to_string(), "some text".to_string()),
+            Resp::Attribute(
+                vec![(Resp::SimpleString("ttl".to_string()), Resp::Integer(3600))],
+                Box::new(Resp::Array(vec![Resp::Integer(7)])),
+            ),
+        ]);
+        let bytes = resp.encode_to_bytes();
+        let (remainder, decoded) = tokenize_bytes(&bytes).unwrap();
+        assert!(remainder.is_empty());
+        assert_eq!(decoded, resp);
+    }
 }